@@ -0,0 +1,143 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// ATTACHMENTS
+// Small files attached to a task. The bytes live on disk under `<dir>/<task id>/<name>`
+// while the task keeps a reference to them. Names double as file names, so they are limited
+// to a safe character set, and the type comes from the extension rather than the client.
+pub const DEFAULT_ATTACHMENTS_DIR: &str = "attachments";
+// Sent base64-encoded, so the request body is a third larger; keep this well under
+// TR_JSON_LIMIT_BYTES.
+pub const DEFAULT_MAX_ATTACHMENT_BYTES: usize = 512 * 1024;
+pub const MAX_ATTACHMENTS_PER_TASK: usize = 10;
+const MAX_ATTACHMENT_NAME_LEN: usize = 100;
+
+const ALLOWED_TYPES: [(&str, &str); 9] = [
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("json", "application/json"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AttachmentError {
+    InvalidName,
+    UnsupportedType,
+    InvalidContent,
+    TooLarge(usize),
+    TooMany,
+}
+
+impl fmt::Display for AttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttachmentError::InvalidName => write!(
+                f,
+                "Attachment names must be 1 to {MAX_ATTACHMENT_NAME_LEN} letters, digits, \
+                 '.', '-' or '_', and not start with '.'"
+            ),
+            AttachmentError::UnsupportedType => {
+                let extensions: Vec<&str> = ALLOWED_TYPES.iter().map(|(ext, _)| *ext).collect();
+                write!(f, "Attachments must be one of: {}", extensions.join(", "))
+            }
+            AttachmentError::InvalidContent => write!(f, "Attachment content must be base64"),
+            AttachmentError::TooLarge(max) => {
+                write!(f, "Attachments must be at most {max} bytes")
+            }
+            AttachmentError::TooMany => write!(
+                f,
+                "A task can have at most {MAX_ATTACHMENTS_PER_TASK} attachments"
+            ),
+        }
+    }
+}
+
+// Checks the name and returns the content type its extension stands for.
+pub fn content_type_for(name: &str) -> Result<&'static str, AttachmentError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_ATTACHMENT_NAME_LEN
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    if !valid {
+        return Err(AttachmentError::InvalidName);
+    }
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .ok_or(AttachmentError::UnsupportedType)?;
+    ALLOWED_TYPES
+        .iter()
+        .find(|(allowed, _)| *allowed == extension)
+        .map(|(_, content_type)| *content_type)
+        .ok_or(AttachmentError::UnsupportedType)
+}
+
+// Rejects content that is obviously too large before spending time decoding it.
+pub fn decode_content(content: &str, max_bytes: usize) -> Result<Vec<u8>, AttachmentError> {
+    if content.len() / 4 * 3 > max_bytes + 2 {
+        return Err(AttachmentError::TooLarge(max_bytes));
+    }
+    let bytes = BASE64
+        .decode(content)
+        .map_err(|_| AttachmentError::InvalidContent)?;
+    if bytes.len() > max_bytes {
+        return Err(AttachmentError::TooLarge(max_bytes));
+    }
+    Ok(bytes)
+}
+
+pub fn attachment_path(dir: &Path, task_id: u64, name: &str) -> PathBuf {
+    dir.join(task_id.to_string()).join(name)
+}
+
+// Written next to its final path and renamed over it, so a reader never sees half a file.
+pub async fn write_attachment(
+    dir: &Path,
+    task_id: u64,
+    name: &str,
+    bytes: &[u8],
+) -> io::Result<()> {
+    let path = attachment_path(dir, task_id, name);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp_path = path.with_file_name(format!("{name}.tmp"));
+    tokio::fs::write(&tmp_path, bytes).await?;
+    tokio::fs::rename(&tmp_path, &path).await
+}
+
+// Removes the files of tasks that no longer exist, whichever way they went: purged from
+// the trash, deleted with their account or compacted away. Returns how many tasks' files
+// were removed.
+pub async fn prune_attachments(dir: &Path, task_ids: &HashSet<u64>) -> io::Result<usize> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let mut pruned = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let stale = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u64>().ok())
+            .is_some_and(|id| !task_ids.contains(&id));
+        if stale {
+            tokio::fs::remove_dir_all(entry.path()).await?;
+            pruned += 1;
+        }
+    }
+    Ok(pruned)
+}
@@ -0,0 +1,70 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use std::path::PathBuf;
+
+// AUDIT LOG
+// One JSON line per mutation, appended to a file that is never rewritten. Handlers hand
+// events to `AppState::audit`; a background task does the writing so requests never wait
+// on the disk.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    CreateTask,
+    UpdateTask,
+    DeleteTask,
+    RestoreTask,
+    Register,
+    ChangePassword,
+    SetRole,
+    DeleteUser,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditEvent {
+    pub at: DateTime<Utc>,
+    pub actor: u64,
+    pub action: AuditAction,
+    // A task id for task actions, a user id for account actions.
+    pub target: u64,
+}
+
+impl AuditEvent {
+    pub fn new(actor: u64, action: AuditAction, target: u64) -> Self {
+        Self {
+            at: Utc::now(),
+            actor,
+            action,
+            target,
+        }
+    }
+}
+
+pub const DEFAULT_AUDIT_LOG_PATH: &str = "audit.log";
+
+pub async fn write_audit_log(path: PathBuf, mut events: mpsc::UnboundedReceiver<AuditEvent>) {
+    let mut file = match OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+    {
+        Ok(file) => file,
+        Err(err) => {
+            tracing::error!(%err, path = %path.display(), "failed to open the audit log");
+            return;
+        }
+    };
+    while let Some(event) = events.recv().await {
+        let Ok(mut line) = serde_json::to_string(&event) else {
+            continue;
+        };
+        line.push('\n');
+        if let Err(err) = file.write_all(line.as_bytes()).await {
+            tracing::error!(%err, "failed to write to the audit log");
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use actix_web::dev::Payload;
+use actix_web::{http::header, web, FromRequest, HttpRequest};
+use argon2::password_hash::{
+    rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+};
+use argon2::Argon2;
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::ApiError;
+use crate::models::Role;
+use crate::state::AppState;
+
+// PASSWORD HASHING
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+// PASSWORD POLICY
+pub const DEFAULT_MIN_PASSWORD_LEN: usize = 8;
+
+// Compared case-insensitively; long enough passwords from this list are still trivially guessed.
+const COMMON_PASSWORDS: [&str; 12] = [
+    "password",
+    "password1",
+    "12345678",
+    "123456789",
+    "1234567890",
+    "qwertyuiop",
+    "iloveyou",
+    "sunshine",
+    "football",
+    "baseball",
+    "letmein1",
+    "trustno1",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum PasswordError {
+    TooShort(usize),
+    TooCommon,
+}
+
+impl fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordError::TooShort(min_len) => {
+                write!(f, "Password must be at least {min_len} characters")
+            }
+            PasswordError::TooCommon => write!(f, "Password is too common"),
+        }
+    }
+}
+
+// Runs before hashing, on registration and on password change.
+pub fn check_password_strength(password: &str, min_len: usize) -> Result<(), PasswordError> {
+    if password.chars().count() < min_len {
+        return Err(PasswordError::TooShort(min_len));
+    }
+    if COMMON_PASSWORDS
+        .iter()
+        .any(|common| common.eq_ignore_ascii_case(password))
+    {
+        return Err(PasswordError::TooCommon);
+    }
+    Ok(())
+}
+
+// The digest comparison inside `verify_password` is constant-time.
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Hash of no one's password, made with the same parameters as real ones.
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password("no such user").expect("hashing a fixed password succeeds"));
+
+// Does the work of a failed `verify_password` for a username that doesn't exist, so the
+// response takes as long as for a wrong password and doesn't tell which names are taken.
+pub fn verify_unknown_user(password: &str) {
+    verify_password(password, &DUMMY_HASH);
+}
+
+// AUTH TOKENS
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Claims {
+    pub sub: u64,
+    exp: u64,
+    // Unique token id, so a single token can be revoked on logout.
+    jti: String,
+    // Tokens issued before roles existed carry no role claim.
+    #[serde(default)]
+    pub role: Role,
+}
+
+pub const DEFAULT_TOKEN_MINUTES: u64 = 60;
+pub const DEFAULT_REFRESH_TOKEN_DAYS: u64 = 30;
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// SIGNING KEYS
+// Tokens are signed with the current key and carry its id in the `kid` header; they verify
+// against whichever key that id names. Rotating means adding a new key in front and dropping
+// the old one once the tokens it signed have expired. Only the HMAC algorithms are supported.
+//
+// TR_JWT_KEYS is a comma-separated list of `kid:secret`, current key first. Without it the
+// single TR_JWT_SECRET is used under the id `default`.
+const DEFAULT_KEY_ID: &str = "default";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyringError {
+    Missing,
+    UnsupportedAlgorithm(String),
+    InvalidKey(String),
+    DuplicateKeyId(String),
+}
+
+impl fmt::Display for KeyringError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyringError::Missing => {
+                write!(
+                    f,
+                    "TR_JWT_KEYS or TR_JWT_SECRET must be set to sign login tokens"
+                )
+            }
+            KeyringError::UnsupportedAlgorithm(name) => write!(
+                f,
+                "unsupported TR_JWT_ALGORITHM {name:?}, expected HS256, HS384 or HS512"
+            ),
+            KeyringError::InvalidKey(entry) => {
+                write!(
+                    f,
+                    "invalid TR_JWT_KEYS entry {entry:?}, expected kid:secret"
+                )
+            }
+            KeyringError::DuplicateKeyId(kid) => write!(f, "duplicate key id {kid:?}"),
+        }
+    }
+}
+
+struct SigningKey {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+pub struct Keyring {
+    algorithm: Algorithm,
+    current: String,
+    keys: HashMap<String, SigningKey>,
+    // How long an access token is valid for; TR_JWT_EXP_MINUTES.
+    pub token_lifetime: Duration,
+}
+
+impl Keyring {
+    // `keys` is (kid, secret) pairs, current key first.
+    pub fn new(algorithm: Algorithm, keys: Vec<(String, String)>) -> Result<Self, KeyringError> {
+        if !matches!(
+            algorithm,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(KeyringError::UnsupportedAlgorithm(format!("{algorithm:?}")));
+        }
+        let current = keys.first().ok_or(KeyringError::Missing)?.0.clone();
+        let mut keyring = HashMap::new();
+        for (kid, secret) in keys {
+            let key = SigningKey {
+                encoding: EncodingKey::from_secret(secret.as_bytes()),
+                decoding: DecodingKey::from_secret(secret.as_bytes()),
+            };
+            if keyring.insert(kid.clone(), key).is_some() {
+                return Err(KeyringError::DuplicateKeyId(kid));
+            }
+        }
+        Ok(Self {
+            algorithm,
+            current,
+            keys: keyring,
+            token_lifetime: Duration::from_secs(DEFAULT_TOKEN_MINUTES * 60),
+        })
+    }
+
+    pub fn from_env() -> Result<Self, KeyringError> {
+        let algorithm = match env::var("TR_JWT_ALGORITHM") {
+            Ok(name) => name
+                .parse()
+                .map_err(|_| KeyringError::UnsupportedAlgorithm(name))?,
+            Err(_) => Algorithm::HS256,
+        };
+        let keys = match env::var("TR_JWT_KEYS") {
+            Ok(list) => list
+                .split(',')
+                .map(|entry| match entry.trim().split_once(':') {
+                    Some((kid, secret)) if !kid.is_empty() && !secret.is_empty() => {
+                        Ok((kid.to_string(), secret.to_string()))
+                    }
+                    _ => Err(KeyringError::InvalidKey(entry.trim().to_string())),
+                })
+                .collect::<Result<_, _>>()?,
+            Err(_) => match env::var("TR_JWT_SECRET") {
+                Ok(secret) => vec![(DEFAULT_KEY_ID.to_string(), secret)],
+                Err(_) => return Err(KeyringError::Missing),
+            },
+        };
+        Self::new(algorithm, keys)
+    }
+
+    pub fn issue(&self, user_id: u64, role: Role) -> String {
+        let claims = Claims {
+            sub: user_id,
+            exp: unix_now() + self.token_lifetime.as_secs(),
+            jti: Uuid::new_v4().to_string(),
+            role,
+        };
+        let header = Header {
+            kid: Some(self.current.clone()),
+            ..Header::new(self.algorithm)
+        };
+        encode(&header, &claims, &self.keys[&self.current].encoding)
+            .expect("HMAC signing does not fail")
+    }
+
+    // Tokens issued before key ids existed have no `kid`; they were signed with the single
+    // TR_JWT_SECRET, which is the current key until the first rotation.
+    pub fn verify(&self, token: &str) -> Option<Claims> {
+        let kid = decode_header(token).ok()?.kid;
+        let key = self.keys.get(kid.as_deref().unwrap_or(&self.current))?;
+        decode::<Claims>(token, &key.decoding, &Validation::new(self.algorithm))
+            .ok()
+            .map(|data| data.claims)
+    }
+}
+
+// Extractor for handlers that require a valid, unrevoked `Authorization: Bearer <token>`
+// header.
+pub struct AuthenticatedUser {
+    pub user_id: u64,
+    pub token_id: String,
+    pub token_expires_at: u64,
+    pub role: Role,
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let app_state = req.app_data::<web::Data<AppState>>();
+        let claims = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .zip(app_state)
+            .and_then(|(token, app_state)| app_state.keyring.verify(token).zip(Some(app_state)))
+            .filter(|(claims, app_state)| !app_state.is_revoked(&claims.jti))
+            // A deleted account's other tokens stop working along with it, and the role is
+            // the stored one, so a promotion or demotion applies to tokens already out.
+            .and_then(|(claims, app_state)| {
+                let role = app_state.read_db().get_user(&claims.sub)?.role;
+                Some((claims, role))
+            });
+
+        ready(match claims {
+            Some((claims, role)) => Ok(AuthenticatedUser {
+                user_id: claims.sub,
+                token_id: claims.jti,
+                token_expires_at: claims.exp,
+                role,
+            }),
+            None => Err(ApiError::unauthorized("Missing or invalid token")),
+        })
+    }
+}
+
+// Extractor for admin-only handlers.
+pub struct AdminUser(pub AuthenticatedUser);
+
+impl FromRequest for AdminUser {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        ready(
+            AuthenticatedUser::from_request(req, payload)
+                .into_inner()
+                .and_then(|user| match user.role {
+                    Role::Admin => Ok(AdminUser(user)),
+                    Role::User => Err(ApiError::forbidden()),
+                }),
+        )
+    }
+}
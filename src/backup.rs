@@ -0,0 +1,69 @@
+use actix_web::web;
+use chrono::Utc;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::models::Database;
+use crate::state::AppState;
+
+// BACKUPS
+// Every interval the in-memory database is written to a timestamped JSON file, whatever the
+// storage backend, and all but the newest `keep` files are removed.
+pub const DEFAULT_BACKUP_DIR: &str = "backups";
+pub const DEFAULT_BACKUP_INTERVAL_MINUTES: u64 = 60;
+pub const DEFAULT_BACKUP_KEEP: usize = 24;
+
+const BACKUP_PREFIX: &str = "database-";
+const BACKUP_SUFFIX: &str = ".json";
+
+pub async fn run_backups(
+    app_state: web::Data<AppState>,
+    dir: PathBuf,
+    interval: Duration,
+    keep: usize,
+) {
+    let mut interval = tokio::time::interval(interval);
+    // The first tick fires immediately; there is nothing new to back up at startup.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let snapshot = app_state.read_db().clone();
+        let dir = dir.clone();
+        match web::block(move || write_backup(&snapshot, &dir, keep)).await {
+            Ok(Ok(path)) => tracing::info!(path = %path.display(), "database backed up"),
+            Ok(Err(err)) => tracing::error!(%err, "failed to back up the database"),
+            Err(err) => tracing::error!(%err, "failed to back up the database"),
+        }
+    }
+}
+
+pub fn write_backup(db: &Database, dir: &Path, keep: usize) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    // Fixed-width UTC timestamps sort by name in the order they were taken.
+    let name = format!(
+        "{BACKUP_PREFIX}{}{BACKUP_SUFFIX}",
+        Utc::now().format("%Y%m%dT%H%M%SZ")
+    );
+    let path = dir.join(name);
+    fs::write(&path, serde_json::to_vec(db)?)?;
+
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX)
+                })
+        })
+        .collect();
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep);
+    for old in &backups[..excess] {
+        fs::remove_file(old)?;
+    }
+    Ok(path)
+}
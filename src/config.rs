@@ -0,0 +1,252 @@
+use actix_cors::Cors;
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::web;
+
+use rustls::ServerConfig;
+
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::error::ApiError;
+
+pub const DEFAULT_BIND_ADDR: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080));
+pub const SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_AUTH_RATE_BURST: u32 = 5;
+pub const DEFAULT_AUTH_RATE_PER_MINUTE: u32 = 10;
+
+pub const DEFAULT_JSON_LIMIT_BYTES: usize = 1024 * 1024;
+// `/tasks/import` reads NDJSON rather than JSON, so it has a limit of its own.
+pub const DEFAULT_IMPORT_LIMIT_BYTES: usize = 16 * 1024 * 1024;
+pub const DEFAULT_TRASH_RETENTION_DAYS: u32 = 30;
+pub const DEFAULT_MAX_TASKS_PER_USER: usize = 10_000;
+// Short, since clients polling `/tasks` want to see their own changes promptly.
+pub const DEFAULT_LIST_MAX_AGE_SECS: u32 = 5;
+// How long a handler may take before the client is answered 504; 0 waits forever.
+pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 10;
+// Requests taking longer are logged at WARN; 0 turns the log off.
+pub const DEFAULT_SLOW_REQUEST_MS: u64 = 1000;
+// Writes handled at once before more are turned away with 503; 0 lifts the limit.
+pub const DEFAULT_MAX_CONCURRENT_WRITES: usize = 64;
+
+// Caps JSON request bodies and turns body errors into `ApiError`s instead of Actix's
+// plain-text defaults.
+pub fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(limit)
+        .error_handler(|err, _req| {
+            let api_error = match &err {
+                JsonPayloadError::Overflow { .. }
+                | JsonPayloadError::OverflowKnownLength { .. } => {
+                    ApiError::payload_too_large(format!("Request body is too large: {err}"))
+                }
+                JsonPayloadError::ContentType => ApiError::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "unsupported_media_type",
+                    "Expected a Content-Type of application/json",
+                ),
+                // serde's message names the field or position that failed.
+                JsonPayloadError::Deserialize(err) => ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_json",
+                    format!("Invalid JSON body: {err}"),
+                ),
+                _ => ApiError::bad_request(format!("Could not read request body: {err}")),
+            };
+            api_error.into()
+        })
+}
+
+// Query strings that don't fit, like an unknown `sort`, get an `ApiError` too; serde's
+// message says which parameter was wrong.
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err, _req| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_query",
+            format!("Invalid query string: {err}"),
+        )
+        .into()
+    })
+}
+
+// A path segment that can't be an id, like `/task/abc`, names nothing that exists.
+pub fn path_config() -> web::PathConfig {
+    web::PathConfig::default().error_handler(|_err, _req| ApiError::not_found().into())
+}
+
+// Reads and parses an optional setting, failing startup on a malformed value rather than
+// silently running with the default.
+pub fn env_opt<T>(key: &str) -> io::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match env::var(key) {
+        Ok(value) => value.parse().map(Some).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid {key}={value:?}: {err}"),
+            )
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+pub fn env_or<T>(key: &str, default: T) -> io::Result<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    Ok(env_opt(key)?.unwrap_or(default))
+}
+
+// Like `env_or`, for settings where 0 would lock everyone out or make no sense.
+pub fn env_nonzero<T>(key: &str, default: T) -> io::Result<T>
+where
+    T: FromStr + Default + PartialEq,
+    T::Err: fmt::Display,
+{
+    let value = env_or(key, default)?;
+    if value == T::default() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{key} must not be 0"),
+        ));
+    }
+    Ok(value)
+}
+
+// Correlation id generated for every request and echoed back to the client.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// Carries the total of a paginated list whose body has nowhere to put it, like CSV.
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+// Lets clients retry `POST /task` without creating the task twice.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+// Seconds the client is willing to wait; it can shorten the server's timeout, not extend it.
+pub const REQUEST_TIMEOUT_HEADER: &str = "x-request-timeout";
+
+// Hosts, by name or address, that users' webhooks may reach although they're internal;
+// empty unless TR_WEBHOOK_ALLOWED_HOSTS is set.
+pub fn webhook_allowed_hosts() -> Vec<String> {
+    env_list("TR_WEBHOOK_ALLOWED_HOSTS")
+}
+
+// Usernames that are made admins, from TR_ADMIN_USERNAMES. This is how the first admin gets
+// in: list their name, then register (or restart, if the account already exists).
+pub fn admin_usernames() -> Vec<String> {
+    env_list("TR_ADMIN_USERNAMES")
+}
+
+// A comma-separated variable; unset means empty.
+fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|host| !host.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// Empty unless TR_CORS_ORIGINS is set; see `build_cors` for what that means.
+pub fn cors_origins() -> io::Result<Vec<String>> {
+    let Ok(value) = env::var("TR_CORS_ORIGINS") else {
+        return Ok(Vec::new());
+    };
+    value
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/'))
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| {
+            if origin.starts_with("http://") || origin.starts_with("https://") {
+                Ok(origin.to_string())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid origin in TR_CORS_ORIGINS: {origin:?}"),
+                ))
+            }
+        })
+        .collect()
+}
+
+// HTTPS when TR_TLS_CERT and TR_TLS_KEY both point at PEM files: the certificate chain,
+// leaf first, and its private key. Neither set serves plain HTTP; only one is a mistake.
+pub fn tls_config() -> io::Result<Option<ServerConfig>> {
+    let (cert_path, key_path) = match (env::var("TR_TLS_CERT"), env::var("TR_TLS_KEY")) {
+        (Ok(cert_path), Ok(key_path)) => (cert_path, key_path),
+        (Err(_), Err(_)) => return Ok(None),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TR_TLS_CERT and TR_TLS_KEY must be set together",
+            ))
+        }
+    };
+    let invalid = |what: &str, path: &str, err: &dyn fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid TLS {what} {path:?}: {err}"),
+        )
+    };
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&cert_path)?))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| invalid("certificate", &cert_path, &err))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&key_path)?))
+        .map_err(|err| invalid("key", &key_path, &err))?
+        .ok_or_else(|| invalid("key", &key_path, &"no private key found"))?;
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map(Some)
+        .map_err(|err| invalid("certificate", &cert_path, &err))
+}
+
+fn is_localhost_origin(origin: &HeaderValue) -> bool {
+    let origin = origin.as_bytes();
+    origin == b"http://localhost" || origin.starts_with(b"http://localhost:")
+}
+
+// Only the listed origins may make credentialed cross-origin requests; anything else is
+// rejected rather than reflected. Without an allowlist, debug builds accept
+// http://localhost on any port for local frontend development and release builds accept
+// no cross-origin requests at all.
+pub fn build_cors(origins: &[String]) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(vec!["GET", "POST", "DELETE", "PUT", "PATCH"])
+        .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
+        .allowed_header(header::CONTENT_TYPE)
+        .allowed_header(HeaderName::from_static(IDEMPOTENCY_KEY_HEADER))
+        .allowed_header(HeaderName::from_static(REQUEST_TIMEOUT_HEADER))
+        // Conditional requests, with the validators the responses expose below.
+        .allowed_headers(vec![
+            header::IF_NONE_MATCH,
+            header::IF_MATCH,
+            header::IF_MODIFIED_SINCE,
+        ])
+        .expose_headers(vec![
+            HeaderName::from_static(REQUEST_ID_HEADER),
+            HeaderName::from_static(TOTAL_COUNT_HEADER),
+            header::RETRY_AFTER,
+            header::LOCATION,
+            header::ETAG,
+            header::LAST_MODIFIED,
+        ])
+        .supports_credentials()
+        .max_age(3600);
+    for origin in origins {
+        cors = cors.allowed_origin(origin);
+    }
+    if origins.is_empty() && cfg!(debug_assertions) {
+        cors = cors.allowed_origin_fn(|origin, _req_head| is_localhost_origin(origin));
+    }
+    cors
+}
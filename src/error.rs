@@ -0,0 +1,296 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::{Error, HttpResponse, ResponseError};
+use futures_util::future::{ready, Either};
+use futures_util::FutureExt;
+use serde_json::{json, Value};
+use validator::ValidationErrors;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+use crate::attachments::AttachmentError;
+use crate::auth::PasswordError;
+use crate::config::REQUEST_TIMEOUT_HEADER;
+use crate::models::{DependencyError, ListError};
+use crate::webhook::WebhookUrlError;
+
+// API ERRORS
+// Every failure is returned as `{"error": {"code": ..., "message": ...}}`, so clients can
+// branch on `code` rather than on the status or the wording of the message.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+    retry_after: Option<Duration>,
+    // Every rule each field broke, for validation failures.
+    fields: Option<BTreeMap<String, Vec<String>>>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            retry_after: None,
+            fields: None,
+        }
+    }
+
+    // 400 with `"fields": {"name": ["..."]}` alongside the usual code and message.
+    pub fn invalid_fields(code: &'static str, errors: &ValidationErrors) -> Self {
+        let fields = errors
+            .field_errors()
+            .into_iter()
+            .map(|(field, errors)| {
+                let messages = errors.iter().map(ToString::to_string).collect();
+                (field.into_owned(), messages)
+            })
+            .collect();
+        Self {
+            fields: Some(fields),
+            ..Self::new(StatusCode::BAD_REQUEST, code, errors.to_string())
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    pub fn forbidden() -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", "Access denied")
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", "Not found")
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, "conflict", message)
+    }
+
+    pub fn payload_too_large(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", message)
+    }
+
+    pub fn too_many_requests(retry_after: Duration) -> Self {
+        Self {
+            retry_after: Some(retry_after),
+            ..Self::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "too_many_requests",
+                "Too many attempts, try again later",
+            )
+        }
+    }
+
+    pub fn read_only() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "read_only",
+            "The service is in read-only maintenance mode; try again later",
+        )
+    }
+
+    pub fn overloaded(retry_after: Duration) -> Self {
+        Self {
+            retry_after: Some(retry_after),
+            ..Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "overloaded",
+                "Too many writes in progress, try again shortly",
+            )
+        }
+    }
+
+    pub fn gateway_timeout() -> Self {
+        Self::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            "timeout",
+            "The request took too long to handle",
+        )
+    }
+
+    // The cause is logged rather than returned so internals don't leak to clients.
+    pub fn internal(err: impl fmt::Display) -> Self {
+        tracing::error!(%err, "internal error");
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            "Internal server error",
+        )
+    }
+
+    // The `error` object of the response body.
+    pub fn to_json(&self) -> Value {
+        let mut error = json!({ "code": self.code, "message": self.message });
+        if let Some(fields) = &self.fields {
+            error["fields"] = json!(fields);
+        }
+        error
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        if let Some(retry_after) = self.retry_after {
+            let seconds = retry_after.as_secs_f64().ceil() as u64;
+            response.insert_header((header::RETRY_AFTER, seconds.max(1).to_string()));
+        }
+        response.json(json!({ "error": self.to_json() }))
+    }
+}
+
+// Middleware turning a panicking handler into a logged `internal` error instead of a reset
+// connection. Wrap it inside the request span so the log line carries the request id. The
+// error is rendered by Actix once it reaches the top, so outer middleware sees an `Err`.
+pub fn catch_panics<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    AssertUnwindSafe(srv.call(req))
+        .catch_unwind()
+        .map(|response| {
+            response.unwrap_or_else(|panic| {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("non-string payload");
+                Err(ApiError::internal(format_args!("handler panicked: {message}")).into())
+            })
+        })
+}
+
+// Middleware answering 504 once a handler runs past `limit`, or past the shorter wait the
+// client asked for in `X-Request-Timeout`. Only the handler is timed: a streamed body, like
+// `/events`, keeps flowing after its headers went out.
+pub fn enforce_timeout<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+    limit: Duration,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    match requested_timeout(&req).map(|requested| requested.map_or(limit, |r| r.min(limit))) {
+        Ok(limit) => {
+            let response = srv.call(req);
+            Either::Left(async move {
+                tokio::time::timeout(limit, response)
+                    .await
+                    .unwrap_or_else(|_| Err(ApiError::gateway_timeout().into()))
+            })
+        }
+        Err(err) => Either::Right(ready(Err(err.into()))),
+    }
+}
+
+fn requested_timeout(req: &ServiceRequest) -> Result<Option<Duration>, ApiError> {
+    let Some(value) = req.headers().get(REQUEST_TIMEOUT_HEADER) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .filter(|timeout| !timeout.is_zero())
+        .map(Some)
+        .ok_or_else(|| {
+            ApiError::bad_request("X-Request-Timeout must be a positive number of seconds")
+        })
+}
+
+impl From<ValidationErrors> for ApiError {
+    fn from(errors: ValidationErrors) -> Self {
+        ApiError::invalid_fields("invalid_task", &errors)
+    }
+}
+
+impl From<DependencyError> for ApiError {
+    fn from(err: DependencyError) -> Self {
+        let (status, code) = match err {
+            DependencyError::Unknown(_) => (StatusCode::BAD_REQUEST, "unknown_dependency"),
+            DependencyError::Cycle => (StatusCode::BAD_REQUEST, "dependency_cycle"),
+            DependencyError::Unfinished(_) => (StatusCode::CONFLICT, "unfinished_dependency"),
+        };
+        ApiError::new(status, code, err.to_string())
+    }
+}
+
+impl From<ListError> for ApiError {
+    fn from(err: ListError) -> Self {
+        let (status, code) = match err {
+            ListError::InvalidName => (StatusCode::BAD_REQUEST, "invalid_list_name"),
+            ListError::NameTaken => (StatusCode::CONFLICT, "list_name_taken"),
+            ListError::Unknown(_) => (StatusCode::BAD_REQUEST, "unknown_list"),
+            ListError::NotEmpty(_) => (StatusCode::CONFLICT, "list_not_empty"),
+        };
+        ApiError::new(status, code, err.to_string())
+    }
+}
+
+impl From<AttachmentError> for ApiError {
+    fn from(err: AttachmentError) -> Self {
+        let (status, code) = match err {
+            AttachmentError::InvalidName | AttachmentError::InvalidContent => {
+                (StatusCode::BAD_REQUEST, "invalid_attachment")
+            }
+            AttachmentError::UnsupportedType => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_attachment_type",
+            ),
+            AttachmentError::TooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, "attachment_too_large"),
+            AttachmentError::TooMany => (StatusCode::CONFLICT, "too_many_attachments"),
+        };
+        ApiError::new(status, code, err.to_string())
+    }
+}
+
+impl From<WebhookUrlError> for ApiError {
+    fn from(err: WebhookUrlError) -> Self {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_webhook_url",
+            err.to_string(),
+        )
+    }
+}
+
+impl From<PasswordError> for ApiError {
+    fn from(err: PasswordError) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "weak_password", err.to_string())
+    }
+}
+
+pub fn version_mismatch(current: u64) -> ApiError {
+    ApiError::new(
+        StatusCode::CONFLICT,
+        "version_mismatch",
+        format!("Task was modified concurrently; the current version is {current}"),
+    )
+}
@@ -0,0 +1,25 @@
+use serde::Serialize;
+
+// TASK CHANGE EVENTS
+// Handlers publish one of these after every successful task mutation; live connections
+// subscribe and forward the ones that belong to their user. Clients refetch the task if
+// they need more than the id.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskEventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskEvent {
+    #[serde(rename = "type")]
+    pub kind: TaskEventKind,
+    pub task_id: u64,
+    #[serde(skip)]
+    pub owner_id: u64,
+}
+
+// Subscribers that fall this far behind miss events instead of holding up publishers.
+pub const EVENT_CHANNEL_CAPACITY: usize = 256;
@@ -0,0 +1,69 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::auth::AdminUser;
+use crate::error::ApiError;
+use crate::state::AppState;
+
+#[derive(Deserialize, Debug)]
+pub struct ReadOnlyUpdate {
+    enabled: bool,
+}
+
+// Maintenance switch; TR_READONLY sets the initial value. Not persisted, so a restart goes
+// back to what the environment says.
+pub async fn set_read_only(
+    app_state: web::Data<AppState>,
+    AdminUser(admin): AdminUser,
+    update: web::Json<ReadOnlyUpdate>,
+) -> impl Responder {
+    app_state.set_read_only(update.enabled);
+    tracing::info!(
+        admin = admin.user_id,
+        enabled = update.enabled,
+        "read-only mode changed"
+    );
+    HttpResponse::Ok().json(json!({ "read_only": update.enabled }))
+}
+
+// Tidies the data and rewrites the store straight away: trash past the retention period is
+// purged, dependencies on tasks that are gone are dropped, and the id counter is resynced.
+// Sizes are in bytes and null when the backend can't report them.
+pub async fn compact(
+    app_state: web::Data<AppState>,
+    AdminUser(admin): AdminUser,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let before = app_state.storage.size().await.map_err(ApiError::internal)?;
+    let stats = app_state
+        .write_db()
+        .compact(Utc::now() - app_state.trash_retention);
+    app_state.save().await.map_err(ApiError::internal)?;
+    app_state
+        .storage
+        .compact()
+        .await
+        .map_err(ApiError::internal)?;
+    let after = app_state.storage.size().await.map_err(ApiError::internal)?;
+    tracing::info!(
+        admin = admin.user_id,
+        purged = stats.purged,
+        before,
+        after,
+        "database compacted"
+    );
+    Ok(HttpResponse::Ok().json(json!({
+        "purged": stats.purged,
+        "dangling_dependencies": stats.dangling_dependencies,
+        "size_before": before,
+        "size_after": after,
+    })))
+}
+
+// Request counts and latencies per route since startup, the same figures `/metrics` exposes
+// but readable without a Prometheus server.
+pub async fn stats(app_state: web::Data<AppState>, _admin: AdminUser) -> impl Responder {
+    HttpResponse::Ok().json(json!({ "routes": app_state.metrics.summary() }))
+}
@@ -0,0 +1,121 @@
+use actix_web::http::header::{self, ContentDisposition};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde::Deserialize;
+
+use std::io;
+
+use crate::attachments::{
+    attachment_path, content_type_for, decode_content, write_attachment, AttachmentError,
+    MAX_ATTACHMENTS_PER_TASK,
+};
+use crate::audit::{AuditAction, AuditEvent};
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::events::TaskEventKind;
+use crate::models::{Attachment, Database, Task};
+use crate::state::AppState;
+
+#[derive(Deserialize, Debug)]
+pub struct NewAttachment {
+    filename: String,
+    // Base64 with padding.
+    content: String,
+}
+
+// The caller's live task, with room for `name`: an attachment of the same name is replaced,
+// so it doesn't count against the limit. Other users' tasks are reported as missing.
+fn attachable<'a>(
+    database: &'a Database,
+    user: &AuthenticatedUser,
+    task_id: u64,
+    name: &str,
+) -> Result<&'a Task, ApiError> {
+    let task = database
+        .get(&task_id)
+        .filter(|task| task.owner_id == user.user_id)
+        .ok_or_else(ApiError::not_found)?;
+    let replacing = task.attachments.iter().any(|a| a.name == name);
+    if !replacing && task.attachments.len() >= MAX_ATTACHMENTS_PER_TASK {
+        return Err(AttachmentError::TooMany.into());
+    }
+    Ok(task)
+}
+
+// The file is written before the task is touched, without holding the lock; the task is
+// checked again afterwards in case it went away meanwhile.
+pub async fn add_attachment(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    task_id: web::Path<u64>,
+    request: web::Json<NewAttachment>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let task_id = task_id.into_inner();
+    let name = request.filename.trim();
+    attachable(&app_state.read_db(), &user, task_id, name)?;
+    let content_type = content_type_for(name)?;
+    let bytes = decode_content(&request.content, app_state.max_attachment_bytes)?;
+    write_attachment(&app_state.attachments_dir, task_id, name, &bytes)
+        .await
+        .map_err(ApiError::internal)?;
+
+    let attachment = Attachment {
+        name: name.to_string(),
+        content_type: content_type.to_string(),
+        size: bytes.len() as u64,
+        created_at: Utc::now(),
+    };
+    {
+        let mut database = app_state.write_db();
+        let mut task = attachable(&database, &user, task_id, name)?.clone();
+        task.attachments
+            .retain(|existing| existing.name != attachment.name);
+        task.attachments.push(attachment.clone());
+        task.touch();
+        database.update(task);
+    }
+    app_state.mark_dirty();
+    app_state.publish(TaskEventKind::Updated, user.user_id, task_id);
+    app_state.audit(AuditEvent::new(
+        user.user_id,
+        AuditAction::UpdateTask,
+        task_id,
+    ));
+    let location = format!("{}/{}", req.path().trim_end_matches('/'), attachment.name);
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, location))
+        .json(attachment))
+}
+
+// Always served as a download, and with sniffing off, so an uploaded file can't be rendered
+// as something it was not declared as.
+pub async fn get_attachment(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(u64, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let (task_id, name) = path.into_inner();
+    let attachment = app_state
+        .read_db()
+        .get(&task_id)
+        .filter(|task| task.owner_id == user.user_id)
+        .and_then(|task| task.attachments.iter().find(|a| a.name == name))
+        .cloned()
+        .ok_or_else(ApiError::not_found)?;
+    let path = attachment_path(&app_state.attachments_dir, task_id, &attachment.name);
+    let bytes = match tokio::fs::read(&path).await {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            tracing::warn!(task_id, name = %attachment.name, "attachment file is missing");
+            return Err(ApiError::not_found());
+        }
+        Err(err) => return Err(ApiError::internal(err)),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(attachment.content_type)
+        .insert_header(ContentDisposition::attachment(attachment.name))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .body(bytes))
+}
@@ -0,0 +1,107 @@
+use actix_web::{web, HttpResponse};
+use chrono::{Duration, Utc};
+use serde_json::json;
+
+use crate::auth::AdminUser;
+use crate::error::ApiError;
+use crate::events::TaskEventKind;
+use crate::models::{Database, Task};
+use crate::state::AppState;
+
+// DEMO ENDPOINTS
+// Only compiled into builds with the `demo` feature, and admin-only even then: both wipe
+// every user's tasks. Release builds don't have the routes at all.
+fn sample_tasks(owner_id: u64) -> Vec<Task> {
+    let now = Utc::now();
+    [
+        json!({ "name": "Write the project proposal", "completed": false, "priority": "high",
+                "tags": ["work"], "due_at": now + Duration::days(2) }),
+        json!({ "name": "Review pull requests", "completed": false, "tags": ["work"],
+                "recurrence": "daily", "due_at": now + Duration::hours(4) }),
+        json!({ "name": "Renew passport", "completed": false, "priority": "high",
+                "tags": ["personal"], "due_at": now - Duration::days(1) }),
+        json!({ "name": "Buy groceries", "completed": false, "priority": "low",
+                "tags": ["personal", "errands"] }),
+        json!({ "name": "Book dentist appointment", "completed": true, "tags": ["personal"] }),
+        json!({ "name": "Plan team offsite", "completed": false, "tags": ["work"],
+                "recurrence": "weekly" }),
+    ]
+    .into_iter()
+    .map(|mut task| {
+        task["owner_id"] = owner_id.into();
+        serde_json::from_value(task).expect("sample task is valid")
+    })
+    .collect()
+}
+
+fn publish_deleted(app_state: &AppState, removed: Vec<Task>) {
+    for task in removed {
+        app_state.publish(TaskEventKind::Deleted, task.owner_id, task.id);
+    }
+}
+
+// Clears every task, then gives the calling admin the fixed sample set.
+pub async fn seed(
+    app_state: web::Data<AppState>,
+    AdminUser(admin): AdminUser,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let (removed, seeded) = {
+        let mut database = app_state.write_db();
+        let removed: Vec<Task> = database.tasks.drain().map(|(_, task)| task).collect();
+        database.history.clear();
+        let seeded: Vec<Task> = sample_tasks(admin.user_id)
+            .into_iter()
+            .filter_map(|task| database.insert_new(task))
+            .collect();
+        (removed, seeded)
+    };
+    app_state.mark_dirty();
+    tracing::warn!(
+        admin = admin.user_id,
+        removed = removed.len(),
+        "database seeded with sample tasks"
+    );
+    publish_deleted(&app_state, removed);
+    for task in &seeded {
+        app_state.publish(TaskEventKind::Created, task.owner_id, task.id);
+    }
+    Ok(HttpResponse::Ok().json(seeded))
+}
+
+// Empties the database: every task and every user except the calling admin, who would
+// otherwise be locked out. Task ids start again from 1.
+pub async fn reset(
+    app_state: web::Data<AppState>,
+    AdminUser(admin): AdminUser,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let (removed, removed_users) = {
+        let mut database = app_state.write_db();
+        let mut old = std::mem::replace(&mut *database, Database::new());
+        if let Some(user) = old.users.remove(&admin.user_id) {
+            database.users.insert(admin.user_id, user);
+        }
+        // The removed users' ids stay retired, like any other deleted account's.
+        database.retired_user_ids = old.retired_user_ids;
+        database.retired_user_ids.extend(old.users.keys());
+        let removed: Vec<Task> = old.tasks.into_values().collect();
+        (removed, old.users.into_keys().collect::<Vec<u64>>())
+    };
+    app_state.mark_dirty();
+    for user_id in &removed_users {
+        app_state.revoke_refresh_tokens(*user_id);
+    }
+    tracing::warn!(
+        admin = admin.user_id,
+        tasks = removed.len(),
+        users = removed_users.len(),
+        "database reset"
+    );
+    let tasks = removed.len();
+    publish_deleted(&app_state, removed);
+    Ok(HttpResponse::Ok().json(json!({
+        "tasks_removed": tasks,
+        "users_removed": removed_users.len(),
+    })))
+}
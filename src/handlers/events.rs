@@ -0,0 +1,105 @@
+use actix_web::http::header::{CacheControl, CacheDirective, ContentEncoding};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use futures_util::{stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{interval_at, Instant};
+
+use std::convert::Infallible;
+use std::time::Duration;
+
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::state::AppState;
+
+// Pushes the caller's task events as JSON text frames until either side closes. The token is
+// only checked on the upgrade request, so an open socket outlives its token's expiry.
+pub async fn task_events_ws(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    req: HttpRequest,
+    body: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    let (response, mut session, mut messages) =
+        actix_ws::handle(&req, body).map_err(|err| ApiError::bad_request(err.to_string()))?;
+    let mut events = app_state.events.subscribe();
+
+    actix_web::rt::spawn(async move {
+        let close_reason = loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) if event.owner_id == user.user_id => {
+                        let Ok(text) = serde_json::to_string(&event) else { continue };
+                        if session.text(text).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!(missed, "websocket subscriber fell behind");
+                    }
+                    Err(RecvError::Closed) => break None,
+                },
+                message = messages.next() => match message {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(reason))) => break reason,
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => break None,
+                },
+            }
+        };
+        let _ = session.close(close_reason).await;
+    });
+
+    Ok(response)
+}
+
+// Comment lines are ignored by `EventSource` but keep proxies from timing out an idle stream.
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+// The same events as the WebSocket, one `data:` line each. When the client goes away actix
+// drops the stream, which drops the subscription with it.
+pub async fn task_events_sse(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> HttpResponse {
+    let events = app_state.events.subscribe();
+    let keep_alive = interval_at(Instant::now() + SSE_KEEP_ALIVE, SSE_KEEP_ALIVE);
+    let frames = stream::unfold(
+        (events, keep_alive),
+        move |(mut events, mut keep_alive)| async move {
+            loop {
+                let frame = tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(event) if event.owner_id == user.user_id => {
+                            let Ok(data) = serde_json::to_string(&event) else { continue };
+                            format!("data: {data}\n\n")
+                        }
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(missed)) => {
+                            tracing::warn!(missed, "event stream subscriber fell behind");
+                            continue;
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                    _ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+                };
+                return Some((
+                    Ok::<_, Infallible>(web::Bytes::from(frame)),
+                    (events, keep_alive),
+                ));
+            }
+        },
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        // Keeps `Compress` from holding events back in its encoder buffer.
+        .insert_header(ContentEncoding::Identity)
+        .streaming(frames)
+}
@@ -0,0 +1,97 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{AuditAction, AuditEvent};
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::events::TaskEventKind;
+use crate::models::{ListError, INBOX_LIST_ID, INBOX_NAME};
+use crate::state::AppState;
+
+#[derive(Deserialize, Debug)]
+pub struct NewList {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteListQuery {
+    // Trash the list's tasks along with it instead of refusing while it has any.
+    #[serde(default)]
+    cascade: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct ListSummary<'a> {
+    id: u64,
+    name: &'a str,
+    // Live tasks only.
+    tasks: usize,
+}
+
+// The inbox first, then the caller's own lists in the order they were created.
+pub async fn get_lists(app_state: web::Data<AppState>, user: AuthenticatedUser) -> HttpResponse {
+    let database = app_state.read_db();
+    let inbox = ListSummary {
+        id: INBOX_LIST_ID,
+        name: INBOX_NAME,
+        tasks: database.list_task_count(user.user_id, &INBOX_LIST_ID),
+    };
+    let lists: Vec<ListSummary> = std::iter::once(inbox)
+        .chain(
+            database
+                .lists_for(user.user_id)
+                .into_iter()
+                .map(|list| ListSummary {
+                    id: list.id,
+                    name: &list.name,
+                    tasks: database.list_task_count(user.user_id, &list.id),
+                }),
+        )
+        .collect();
+    HttpResponse::Ok().json(lists)
+}
+
+pub async fn create_list(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    request: web::Json<NewList>,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let list = app_state
+        .write_db()
+        .create_list(user.user_id, &request.name)?;
+    app_state.mark_dirty();
+    Ok(HttpResponse::Created().json(list))
+}
+
+pub async fn delete_list(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    id: web::Path<u64>,
+    query: web::Query<DeleteListQuery>,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let id = id.into_inner();
+    if id == INBOX_LIST_ID {
+        return Err(ApiError::bad_request("The inbox can't be deleted"));
+    }
+    let trashed = match app_state
+        .write_db()
+        .delete_list(user.user_id, &id, query.cascade)
+    {
+        Ok(trashed) => trashed,
+        // Other users' lists are reported as missing so ids don't leak.
+        Err(ListError::Unknown(_)) => return Err(ApiError::not_found()),
+        Err(err) => return Err(err.into()),
+    };
+    app_state.mark_dirty();
+    for task_id in trashed {
+        app_state.publish(TaskEventKind::Deleted, user.user_id, task_id);
+        app_state.audit(AuditEvent::new(
+            user.user_id,
+            AuditAction::DeleteTask,
+            task_id,
+        ));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,228 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::DefaultHeaders;
+use actix_web::{web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::{path_config, query_config};
+use crate::error::ApiError;
+use crate::state::AppState;
+
+pub mod admin;
+pub mod attachments;
+#[cfg(feature = "demo")]
+pub mod demo;
+pub mod events;
+pub mod lists;
+pub mod tasks;
+pub mod users;
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 200;
+
+#[derive(Deserialize, Debug)]
+pub struct Pagination {
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    // Keyset cursor: the last id already seen. Unlike an offset, inserts and deletes between
+    // requests can't shift it. Only `/tasks` honours it, and only in id order.
+    pub after: Option<u64>,
+}
+
+// Per-item outcomes of a bulk request, by position in the request: the array index for a
+// JSON body, the zero-based line for NDJSON. Items fail on their own, with the error they
+// would have got if sent alone; only a request that can't be read at all fails as a whole.
+pub struct BulkResult<T> {
+    outcomes: Vec<(usize, Result<T, ApiError>)>,
+}
+
+#[derive(Serialize)]
+struct BulkItem<'a, T> {
+    index: usize,
+    #[serde(flatten)]
+    value: Option<&'a T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Value>,
+}
+
+// 200 when everything succeeded, 207 Multi-Status when anything failed.
+fn bulk_status(failed: usize) -> StatusCode {
+    if failed == 0 {
+        StatusCode::OK
+    } else {
+        StatusCode::MULTI_STATUS
+    }
+}
+
+impl<T: Serialize> BulkResult<T> {
+    pub fn push(&mut self, index: usize, outcome: Result<T, ApiError>) {
+        self.outcomes.push((index, outcome));
+    }
+
+    pub fn succeeded(&self) -> impl Iterator<Item = &T> {
+        self.outcomes
+            .iter()
+            .filter_map(|(_, outcome)| outcome.as_ref().ok())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        bulk_status(
+            self.outcomes
+                .iter()
+                .filter(|(_, outcome)| outcome.is_err())
+                .count(),
+        )
+    }
+
+    // `{"results": [...], "succeeded": n, "failed": n}`, where each result has its `index`
+    // and either the item's own fields or an `error` like a whole request would get, plus
+    // its `status`.
+    pub fn to_json(&self) -> Value {
+        let results: Vec<BulkItem<T>> = self
+            .outcomes
+            .iter()
+            .map(|(index, outcome)| BulkItem {
+                index: *index,
+                value: outcome.as_ref().ok(),
+                error: outcome.as_ref().err().map(|err| {
+                    let mut error = err.to_json();
+                    error["status"] = json!(err.status.as_u16());
+                    error
+                }),
+            })
+            .collect();
+        let succeeded = self.succeeded().count();
+        json!({
+            "results": results,
+            "succeeded": succeeded,
+            "failed": self.outcomes.len() - succeeded,
+        })
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::build(self.status()).json(self.to_json())
+    }
+}
+
+// Not derived, which would require `T: Default`.
+impl<T> Default for BulkResult<T> {
+    fn default() -> Self {
+        Self {
+            outcomes: Vec::new(),
+        }
+    }
+}
+
+impl<T> FromIterator<Result<T, ApiError>> for BulkResult<T> {
+    fn from_iter<I: IntoIterator<Item = Result<T, ApiError>>>(outcomes: I) -> Self {
+        Self {
+            outcomes: outcomes.into_iter().enumerate().collect(),
+        }
+    }
+}
+
+pub async fn route_not_found() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::not_found())
+}
+
+// Liveness/readiness probe; deliberately unauthenticated.
+async fn health(app_state: web::Data<AppState>) -> impl Responder {
+    let database = app_state.read_db();
+    HttpResponse::Ok().json(json!({
+        "status": "ok",
+        "tasks": database.task_count(),
+        "users": database.users.len(),
+    }))
+}
+
+pub async fn metrics(app_state: web::Data<AppState>) -> impl Responder {
+    let (tasks, users) = {
+        let database = app_state.read_db();
+        (database.task_count(), database.users.len())
+    };
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(app_state.metrics.render(tasks, users))
+}
+
+// Every API route except `/metrics`. Static paths are registered before `/task/{id}` so they
+// aren't captured as ids.
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/task", web::post().to(tasks::create_task))
+        .route("/tasks", web::get().to(tasks::get_all_tasks))
+        .route("/tasks/search", web::get().to(tasks::search_tasks))
+        .route("/tasks/count", web::get().to(tasks::count_tasks))
+        .route("/tasks/grouped", web::get().to(tasks::grouped_tasks))
+        .route("/tasks/bulk", web::post().to(tasks::bulk_create_tasks))
+        .route("/tasks/delete", web::post().to(tasks::bulk_delete_tasks))
+        .route(
+            "/tasks/completed",
+            web::delete().to(tasks::clear_completed_tasks),
+        )
+        .route("/tasks/export", web::get().to(tasks::export_tasks))
+        .route("/tasks/import", web::post().to(tasks::import_tasks))
+        .route("/task", web::put().to(tasks::update_task))
+        .route("/task/{id}", web::get().to(tasks::get_task))
+        .route("/task/{id}", web::put().to(tasks::replace_task_by_id))
+        .route("/task/{id}", web::delete().to(tasks::delete_task))
+        .route("/task/{id}", web::patch().to(tasks::patch_task))
+        .route(
+            "/task/{id}/attachment",
+            web::post().to(attachments::add_attachment),
+        )
+        .route(
+            "/task/{id}/attachment/{name}",
+            web::get().to(attachments::get_attachment),
+        )
+        .route("/task/{id}/history", web::get().to(tasks::task_history))
+        .route("/task/{id}/restore", web::post().to(tasks::restore_task))
+        .route("/task/{id}/complete", web::post().to(tasks::complete_task))
+        .route(
+            "/task/{id}/uncomplete",
+            web::post().to(tasks::uncomplete_task),
+        )
+        .route("/lists", web::get().to(lists::get_lists))
+        .route("/lists", web::post().to(lists::create_list))
+        .route("/lists/{id}", web::delete().to(lists::delete_list))
+        .route("/ws", web::get().to(events::task_events_ws))
+        .route("/events", web::get().to(events::task_events_sse))
+        .route("/register", web::post().to(users::register))
+        .route("/login", web::post().to(users::login))
+        .route("/refresh", web::post().to(users::refresh))
+        .route("/logout", web::post().to(users::logout))
+        .route("/me", web::get().to(users::me))
+        .route("/me", web::delete().to(users::delete_me))
+        .route("/me/webhook", web::put().to(users::set_webhook))
+        .route("/users", web::get().to(users::list_users))
+        .route("/users/{id}/role", web::put().to(users::set_user_role))
+        .route("/user/password", web::put().to(users::change_password))
+        .route("/admin/read-only", web::put().to(admin::set_read_only))
+        .route("/admin/compact", web::post().to(admin::compact))
+        .route("/admin/stats", web::get().to(admin::stats))
+        .route("/health", web::get().to(health));
+    #[cfg(feature = "demo")]
+    cfg.route("/admin/seed", web::post().to(demo::seed))
+        .route("/admin/reset", web::post().to(demo::reset));
+}
+
+const API_PREFIX: &str = "/api/v1";
+
+// Mounts the API under `/api/v1`. The unversioned paths it replaced keep working for one more
+// release but are marked deprecated. Their scope matches everything, so it has to come last.
+pub fn api(cfg: &mut web::ServiceConfig, metrics_enabled: bool) {
+    cfg.app_data(query_config())
+        .app_data(path_config())
+        .service(web::scope(API_PREFIX).configure(routes));
+    // The metrics endpoint is opt-in so it isn't exposed by accident.
+    if metrics_enabled {
+        cfg.route("/metrics", web::get().to(metrics));
+    }
+    cfg.service(
+        web::scope("")
+            .wrap(DefaultHeaders::new().add(("Deprecation", "true")).add((
+                header::LINK,
+                format!("<{API_PREFIX}>; rel=\"successor-version\""),
+            )))
+            .configure(routes),
+    );
+}
@@ -0,0 +1,979 @@
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{
+    self, Accept, CacheControl, CacheDirective, ContentType, ETag, EntityTag, Header,
+    IfModifiedSince, IfNoneMatch, LastModified,
+};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use validator::{ValidationError, ValidationErrors};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use super::{BulkResult, Pagination, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::audit::{AuditAction, AuditEvent};
+use crate::auth::AuthenticatedUser;
+use crate::config::{IDEMPOTENCY_KEY_HEADER, TOTAL_COUNT_HEADER};
+use crate::error::{version_mismatch, ApiError};
+use crate::events::TaskEventKind;
+use crate::models::{
+    normalize_task, validate, Database, QueryPage, SortKey, SortOrder, Task, TaskCsvRow, TaskPatch,
+    TaskQuery,
+};
+use crate::state::AppState;
+
+#[derive(Deserialize, Debug)]
+pub struct TaskFilter {
+    pub completed: Option<bool>,
+    pub tag: Option<String>,
+    // Incomplete tasks whose due date has passed.
+    overdue: Option<bool>,
+    // 0 is the inbox.
+    list_id: Option<u64>,
+}
+
+// Tasks in the trash are only returned when asked for with `?include_deleted=true`.
+#[derive(Deserialize, Debug)]
+pub struct TrashQuery {
+    #[serde(default)]
+    pub include_deleted: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TaskSort {
+    sort: Option<SortKey>,
+    order: Option<SortOrder>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    #[serde(default)]
+    fuzzy: bool,
+    // Share of the query that may be wrong for a fuzzy match; 0 only allows exact words.
+    threshold: Option<f64>,
+}
+
+const DEFAULT_FUZZY_THRESHOLD: f64 = 0.34;
+
+#[derive(Serialize)]
+struct TaskPage<'a> {
+    tasks: Vec<&'a Task>,
+    total: usize,
+    limit: usize,
+    offset: usize,
+    // Id of the last task on this page, to pass back as `after`; absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<u64>,
+}
+
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+// A repeated `Idempotency-Key` gets the task created by the first request back, as it was
+// then, instead of a second task. Every create holds the write lock while it checks the key,
+// so two concurrent retries can't both get through.
+pub async fn create_task(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    req: HttpRequest,
+    task: web::Json<Task>,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let idempotency_key = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        None => None,
+        Some(value) => match value.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN => {
+                Some(key.to_string())
+            }
+            _ => {
+                return Err(ApiError::bad_request(format!(
+                "Idempotency-Key must be 1 to {MAX_IDEMPOTENCY_KEY_LEN} visible ASCII characters"
+            )))
+            }
+        },
+    };
+    let mut task = task.into_inner();
+    normalize_task(&mut task);
+    validate(&task)?;
+    task.owner_id = user.user_id;
+    let mut database = app_state.write_db();
+    if let Some(task) = idempotency_key
+        .as_deref()
+        .and_then(|key| app_state.idempotent_result(user.user_id, key))
+    {
+        return Ok(created(&req, &task));
+    }
+    app_state.check_task_quota(&database, user.user_id, 1)?;
+    check_new_task(&database, &task)?;
+    let task = database
+        .insert_new(task)
+        .ok_or_else(|| ApiError::conflict("A task with this id already exists"))?;
+    if let Some(key) = idempotency_key {
+        app_state.remember_idempotent_result(user.user_id, key, &task);
+    }
+    app_state.mark_dirty();
+    app_state.publish(TaskEventKind::Created, task.owner_id, task.id);
+    app_state.audit(AuditEvent::new(
+        user.user_id,
+        AuditAction::CreateTask,
+        task.id,
+    ));
+    app_state
+        .metrics
+        .tasks_created
+        .fetch_add(1, Ordering::Relaxed);
+    Ok(created(&req, &task))
+}
+
+// What every new task is checked against, however it comes in: its list and prerequisites
+// have to be the owner's own. Cycles are looked for under the id `insert_new` is about to
+// assign, not whatever id the client sent.
+fn check_new_task(database: &Database, task: &Task) -> Result<(), ApiError> {
+    database.check_list(task)?;
+    let mut unsaved = task.clone();
+    unsaved.id = database.next_id;
+    database.check_dependencies(&unsaved, None)?;
+    Ok(())
+}
+
+// 201 with the new task, and its URL under whichever prefix the request came in on.
+fn created(req: &HttpRequest, task: &Task) -> HttpResponse {
+    let location = format!("{}/{}", req.path().trim_end_matches('/'), task.id);
+    HttpResponse::Created()
+        .insert_header((header::LOCATION, location))
+        .json(task)
+}
+
+// What a successful bulk item reports: the task it created or deleted.
+#[derive(Serialize, Debug)]
+struct BulkTask {
+    id: u64,
+}
+
+// Creates every valid task under one write lock and a single save. Each element is read on
+// its own, so one with the wrong shape is reported by index like one that fails validation,
+// instead of failing the whole batch; only a body that isn't a non-empty array does.
+pub async fn bulk_create_tasks(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    tasks: web::Json<Vec<Value>>,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    if tasks.is_empty() {
+        return Err(ApiError::bad_request("Expected at least one task"));
+    }
+    let mut database = app_state.write_db();
+    let result: BulkResult<BulkTask> = tasks
+        .into_inner()
+        .into_iter()
+        .map(|task| {
+            let mut task: Task = serde_json::from_value(task).map_err(|err| {
+                ApiError::new(StatusCode::BAD_REQUEST, "invalid_json", err.to_string())
+            })?;
+            normalize_task(&mut task);
+            validate(&task)?;
+            task.owner_id = user.user_id;
+            app_state.check_task_quota(&database, user.user_id, 1)?;
+            check_new_task(&database, &task)?;
+            let task = database
+                .insert_new(task)
+                .ok_or_else(|| ApiError::conflict("A task with this id already exists"))?;
+            app_state.publish(TaskEventKind::Created, task.owner_id, task.id);
+            app_state.audit(AuditEvent::new(
+                user.user_id,
+                AuditAction::CreateTask,
+                task.id,
+            ));
+            Ok(BulkTask { id: task.id })
+        })
+        .collect();
+
+    let created = result.succeeded().count() as u64;
+    if created > 0 {
+        app_state.mark_dirty();
+        app_state
+            .metrics
+            .tasks_created
+            .fetch_add(created, Ordering::Relaxed);
+    }
+    Ok(result.response())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BulkDeleteRequest {
+    pub ids: Vec<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BulkDeleteQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+// Ids that don't exist or belong to someone else are both reported as not found; `removed`
+// and `missing` repeat the results as plain id lists. With `?dry_run=true` the response is
+// the same, but nothing is deleted or saved.
+pub async fn bulk_delete_tasks(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    query: web::Query<BulkDeleteQuery>,
+    request: web::Json<BulkDeleteRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if request.ids.is_empty() {
+        return Err(ApiError::bad_request("Expected at least one id"));
+    }
+    if !query.dry_run {
+        app_state.check_writable()?;
+    }
+    let ids = request.into_inner().ids;
+    let mut database = app_state.write_db();
+    let (mut seen, mut missing) = (HashSet::new(), Vec::new());
+    let result: BulkResult<BulkTask> = ids
+        .iter()
+        .map(|&id| {
+            let owned = database
+                .get(&id)
+                .is_some_and(|task| task.owner_id == user.user_id);
+            // A repeated id is only deleted once; the rest are reported as not found.
+            if !owned || !seen.insert(id) {
+                missing.push(id);
+                return Err(ApiError::not_found());
+            }
+            if !query.dry_run {
+                database.delete(&id);
+                app_state.publish(TaskEventKind::Deleted, user.user_id, id);
+                app_state.audit(AuditEvent::new(user.user_id, AuditAction::DeleteTask, id));
+            }
+            Ok(BulkTask { id })
+        })
+        .collect();
+
+    let removed: Vec<u64> = result.succeeded().map(|deleted| deleted.id).collect();
+    if !query.dry_run && !removed.is_empty() {
+        app_state.mark_dirty();
+        app_state
+            .metrics
+            .tasks_deleted
+            .fetch_add(removed.len() as u64, Ordering::Relaxed);
+    }
+    let mut body = result.to_json();
+    body["removed"] = json!(removed);
+    body["missing"] = json!(missing);
+    if query.dry_run {
+        body["dry_run"] = json!(true);
+    }
+    Ok(HttpResponse::build(result.status()).json(body))
+}
+
+pub async fn clear_completed_tasks(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let mut database = app_state.write_db();
+    let deleted = database.clear_completed(user.user_id);
+    for id in &deleted {
+        app_state.publish(TaskEventKind::Deleted, user.user_id, *id);
+        app_state.audit(AuditEvent::new(user.user_id, AuditAction::DeleteTask, *id));
+    }
+    if !deleted.is_empty() {
+        app_state.mark_dirty();
+        app_state
+            .metrics
+            .tasks_deleted
+            .fetch_add(deleted.len() as u64, Ordering::Relaxed);
+    }
+    Ok(HttpResponse::Ok().json(json!({ "deleted": deleted.len() })))
+}
+
+const EXPORT_CHUNK_SIZE: usize = 256;
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+// Streams the caller's tasks as NDJSON. Only the id list is collected up front; tasks are
+// serialized a chunk at a time under a short read lock, so memory stays flat no matter how
+// many tasks there are.
+pub async fn export_tasks(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> impl Responder {
+    let ids: Vec<u64> = app_state
+        .read_db()
+        .get_all(user.user_id, false)
+        .iter()
+        .map(|task| task.id)
+        .collect();
+
+    let chunks = (0..ids.len()).step_by(EXPORT_CHUNK_SIZE);
+    let body = stream::iter(chunks).map(move |start| {
+        let end = (start + EXPORT_CHUNK_SIZE).min(ids.len());
+        let database = app_state.read_db();
+        let mut buffer = Vec::new();
+        for id in &ids[start..end] {
+            // Tasks deleted since the export started are skipped.
+            if let Some(task) = database
+                .get(id)
+                .filter(|task| task.owner_id == user.user_id)
+            {
+                serde_json::to_writer(&mut buffer, task).map_err(ErrorInternalServerError)?;
+                buffer.push(b'\n');
+            }
+        }
+        Ok::<_, actix_web::Error>(web::Bytes::from(buffer))
+    });
+    HttpResponse::Ok()
+        .content_type(NDJSON_CONTENT_TYPE)
+        .streaming(body)
+}
+
+// Parses one NDJSON line into a validated task; blank lines are skipped.
+fn parse_import_line(line: &[u8]) -> Option<Result<Task, ApiError>> {
+    if line.iter().all(u8::is_ascii_whitespace) {
+        return None;
+    }
+    let parsed = serde_json::from_slice::<Task>(line)
+        .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, "invalid_json", err.to_string()))
+        .and_then(|mut task| {
+            normalize_task(&mut task);
+            validate(&task)?;
+            Ok(task)
+        });
+    Some(parsed)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImportQuery {
+    #[serde(default)]
+    upsert: bool,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum UpsertAction {
+    Created,
+    Updated,
+}
+
+#[derive(Serialize, Debug)]
+struct Upserted {
+    // The stored id, which for created tasks is a freshly assigned one.
+    id: u64,
+    action: UpsertAction,
+}
+
+// Accepts the format produced by `/tasks/export`. Every task is stored as a new task owned
+// by the caller, unless `?upsert=true` is given: then lines whose id matches one of the
+// caller's live tasks update it instead. Results are by zero-based line, like any bulk
+// request's; blank lines have none.
+pub async fn import_tasks(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    query: web::Query<ImportQuery>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let mut lines = Vec::new();
+    let mut buffer = Vec::new();
+    let mut line_index = 0;
+    let mut handle_line = |line: &[u8]| {
+        if let Some(parsed) = parse_import_line(line) {
+            lines.push((line_index, parsed));
+        }
+        line_index += 1;
+    };
+    let mut received = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|err| ApiError::bad_request(err.to_string()))?;
+        received += chunk.len();
+        if received > app_state.import_limit_bytes {
+            return Err(ApiError::payload_too_large(format!(
+                "Imports must be at most {} bytes",
+                app_state.import_limit_bytes
+            )));
+        }
+        buffer.extend_from_slice(&chunk);
+        while let Some(newline) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            handle_line(&line);
+        }
+    }
+    handle_line(&buffer);
+
+    let mut database = app_state.write_db();
+    if query.upsert {
+        return Ok(upsert_tasks(&app_state, &mut database, &user, lines)?.response());
+    }
+    // All or nothing, since a partial import would leave the caller guessing which lines made it.
+    let valid = lines.iter().filter(|(_, parsed)| parsed.is_ok()).count();
+    app_state.check_task_quota(&database, user.user_id, valid)?;
+    let mut result = BulkResult::default();
+    for (index, parsed) in lines {
+        let outcome = parsed.and_then(|mut task| {
+            task.owner_id = user.user_id;
+            check_new_task(&database, &task)?;
+            let task = database
+                .insert_new(task)
+                .ok_or_else(|| ApiError::conflict("A task with this id already exists"))?;
+            app_state.publish(TaskEventKind::Created, task.owner_id, task.id);
+            app_state.audit(AuditEvent::new(
+                user.user_id,
+                AuditAction::CreateTask,
+                task.id,
+            ));
+            Ok(BulkTask { id: task.id })
+        });
+        result.push(index, outcome);
+    }
+    let imported = result.succeeded().count() as u64;
+    if imported > 0 {
+        app_state.mark_dirty();
+        app_state
+            .metrics
+            .tasks_created
+            .fetch_add(imported, Ordering::Relaxed);
+    }
+    Ok(result.response())
+}
+
+// Everything happens under the caller's write lock and ends in a single save. Updates follow
+// the rules of PUT, including the version check, but a failing line is reported rather than
+// failing the batch.
+fn upsert_tasks(
+    app_state: &AppState,
+    database: &mut Database,
+    user: &AuthenticatedUser,
+    lines: Vec<(usize, Result<Task, ApiError>)>,
+) -> Result<BulkResult<Upserted>, ApiError> {
+    let owned = |database: &Database, id: u64| {
+        database
+            .get(&id)
+            .is_some_and(|task| task.owner_id == user.user_id)
+    };
+    let creating = lines
+        .iter()
+        .filter(|(_, parsed)| parsed.as_ref().is_ok_and(|task| !owned(database, task.id)))
+        .count();
+    app_state.check_task_quota(database, user.user_id, creating)?;
+
+    let mut result = BulkResult::default();
+    let (mut created, mut updated) = (0, 0);
+    for (index, parsed) in lines {
+        let outcome = parsed.and_then(|mut task| {
+            task.owner_id = user.user_id;
+            let Some(existing) = database
+                .get(&task.id)
+                .filter(|existing| existing.owner_id == user.user_id)
+            else {
+                check_new_task(database, &task)?;
+                let task = database
+                    .insert_new(task)
+                    .ok_or_else(|| ApiError::conflict("A task with this id already exists"))?;
+                app_state.publish(TaskEventKind::Created, task.owner_id, task.id);
+                app_state.audit(AuditEvent::new(
+                    user.user_id,
+                    AuditAction::CreateTask,
+                    task.id,
+                ));
+                created += 1;
+                return Ok(Upserted {
+                    id: task.id,
+                    action: UpsertAction::Created,
+                });
+            };
+            // The stored task has moved on since the imported version was exported.
+            if existing.version != task.version {
+                return Err(version_mismatch(existing.version));
+            }
+            task.created_at = existing.created_at;
+            task.deleted_at = None;
+            task.attachments = existing.attachments.clone();
+            database.check_list(&task)?;
+            database.check_dependencies(&task, Some(existing))?;
+            let just_completed = task.completed && !existing.completed;
+            task.touch();
+            database.update(task.clone());
+            app_state.publish(TaskEventKind::Updated, task.owner_id, task.id);
+            if just_completed {
+                app_state.task_completed(database, &task);
+            }
+            app_state.audit(AuditEvent::new(
+                user.user_id,
+                AuditAction::UpdateTask,
+                task.id,
+            ));
+            updated += 1;
+            Ok(Upserted {
+                id: task.id,
+                action: UpsertAction::Updated,
+            })
+        });
+        result.push(index, outcome);
+    }
+
+    if created + updated > 0 {
+        app_state.mark_dirty();
+        app_state
+            .metrics
+            .tasks_created
+            .fetch_add(created, Ordering::Relaxed);
+    }
+    Ok(result)
+}
+
+// Strong validator derived from the serialized body, so it changes whenever any field does.
+fn entity_tag(body: &[u8]) -> EntityTag {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    EntityTag::new_strong(format!("{:016x}", hasher.finish()))
+}
+
+fn etag_matches(if_none_match: &IfNoneMatch, etag: &EntityTag) -> bool {
+    match if_none_match {
+        IfNoneMatch::Any => true,
+        IfNoneMatch::Items(tags) => tags.iter().any(|tag| tag.weak_eq(etag)),
+    }
+}
+
+pub async fn get_task(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    task_id: web::Path<u64>,
+    trash: web::Query<TrashQuery>,
+    if_none_match: Option<web::Header<IfNoneMatch>>,
+) -> Result<HttpResponse, ApiError> {
+    let body = {
+        let database = app_state.read_db();
+        let task_id = task_id.into_inner();
+        let task = if trash.include_deleted {
+            database.get_including_deleted(&task_id)
+        } else {
+            database.get(&task_id)
+        };
+
+        // Other users' tasks are reported as missing so ids don't leak.
+        match task {
+            Some(task) if task.owner_id == user.user_id => {
+                serde_json::to_vec(task).map_err(ApiError::internal)?
+            }
+            _ => return Err(ApiError::not_found()),
+        }
+    };
+
+    let etag = entity_tag(&body);
+    let not_modified =
+        if_none_match.is_some_and(|if_none_match| etag_matches(&if_none_match, &etag));
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .finish());
+    }
+    Ok(HttpResponse::Ok()
+        .insert_header(ETag(etag))
+        .content_type(ContentType::json())
+        .body(body))
+}
+
+// Earlier versions of one task, newest first; the current version isn't included.
+pub async fn task_history(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    task_id: web::Path<u64>,
+    trash: web::Query<TrashQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let database = app_state.read_db();
+    let task_id = task_id.into_inner();
+    let task = if trash.include_deleted {
+        database.get_including_deleted(&task_id)
+    } else {
+        database.get(&task_id)
+    };
+    match task {
+        Some(task) if task.owner_id == user.user_id => {
+            let history: Vec<&Task> = database.history(&task_id).collect();
+            Ok(HttpResponse::Ok().json(history))
+        }
+        _ => Err(ApiError::not_found()),
+    }
+}
+
+pub async fn get_all_tasks(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    page: web::Query<Pagination>,
+    filter: web::Query<TaskFilter>,
+    trash: web::Query<TrashQuery>,
+    sort: web::Query<TaskSort>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let query = TaskQuery {
+        include_deleted: trash.include_deleted,
+        completed: filter.completed,
+        tag: filter.tag.clone(),
+        overdue: filter.overdue,
+        list_id: filter.list_id,
+        sort: sort.sort.unwrap_or_default(),
+        order: sort.order.unwrap_or_default(),
+        after: page.after,
+        offset: page.offset.unwrap_or(0),
+        limit: page.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE),
+        ..TaskQuery::new(user.user_id)
+    };
+    if query.after.is_some() {
+        if page.offset.is_some() {
+            return Err(ApiError::bad_request("after and offset cannot be combined"));
+        }
+        if query.sort != SortKey::Id {
+            return Err(ApiError::bad_request(
+                "after is only supported with sort=id",
+            ));
+        }
+    }
+
+    let database = app_state.read_db();
+    let QueryPage {
+        tasks,
+        total,
+        next_cursor,
+    } = database.query(&query);
+    // Listings are per user, so only the client itself may cache them.
+    let cache_control = CacheControl(vec![
+        CacheDirective::Private,
+        CacheDirective::MaxAge(app_state.list_max_age_secs),
+    ]);
+    // Whether a task is overdue changes with the clock alone, so those listings have no
+    // meaningful modification date. A date only has whole seconds, so none is sent until the
+    // second of the latest change is over; another change could still land in it unseen.
+    let last_modified = database
+        .last_modified(user.user_id)
+        .filter(|_| query.overdue.is_none())
+        .filter(|last_modified| last_modified.timestamp() < Utc::now().timestamp());
+    let csv = wants_csv(&req);
+    let body = if csv {
+        tasks_to_csv(&tasks)?
+    } else {
+        serde_json::to_vec(&TaskPage {
+            tasks,
+            total,
+            limit: query.limit,
+            offset: query.offset,
+            next_cursor,
+        })
+        .map_err(ApiError::internal)?
+    };
+    drop(database);
+
+    // The tag covers the exact body, so any change shows. As HTTP requires, If-None-Match
+    // wins when both are sent.
+    let etag = entity_tag(&body);
+    let not_modified = if req.headers().contains_key(header::IF_NONE_MATCH) {
+        IfNoneMatch::parse(&req).is_ok_and(|if_none_match| etag_matches(&if_none_match, &etag))
+    } else {
+        last_modified.is_some_and(|last_modified| {
+            IfModifiedSince::parse(&req).is_ok_and(|IfModifiedSince(since)| {
+                last_modified.timestamp()
+                    <= DateTime::<Utc>::from(SystemTime::from(since)).timestamp()
+            })
+        })
+    };
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    // The same URL answers in JSON or CSV, so caches have to key on Accept too.
+    response
+        .insert_header(cache_control)
+        .insert_header(ETag(etag))
+        .insert_header((header::VARY, "Accept"));
+    if let Some(last_modified) = last_modified {
+        response.insert_header(LastModified(SystemTime::from(last_modified).into()));
+    }
+    if not_modified {
+        return Ok(response.finish());
+    }
+    if csv {
+        return Ok(response
+            .content_type(CSV_CONTENT_TYPE)
+            .insert_header((TOTAL_COUNT_HEADER, total))
+            .body(body));
+    }
+    Ok(response.content_type(ContentType::json()).body(body))
+}
+
+const CSV_CONTENT_TYPE: &str = "text/csv; charset=utf-8";
+
+// JSON stays the default; CSV is only picked when the client ranks it above JSON.
+fn wants_csv(req: &HttpRequest) -> bool {
+    let Ok(accept) = Accept::parse(req) else {
+        return false;
+    };
+    accept
+        .ranked()
+        .into_iter()
+        .find(|mime| mime.essence_str() == "text/csv" || mime.essence_str() == "application/json")
+        .is_some_and(|mime| mime.essence_str() == "text/csv")
+}
+
+fn tasks_to_csv(tasks: &[&Task]) -> Result<Vec<u8>, ApiError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    if tasks.is_empty() {
+        writer
+            .write_record(TaskCsvRow::HEADERS)
+            .map_err(ApiError::internal)?;
+    }
+    for task in tasks {
+        writer
+            .serialize(TaskCsvRow::from(*task))
+            .map_err(ApiError::internal)?;
+    }
+    writer.into_inner().map_err(ApiError::internal)
+}
+
+pub async fn search_tasks(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    query: web::Query<SearchQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Err(ApiError::bad_request("Query parameter q is required"));
+    }
+    let database = app_state.read_db();
+    if query.fuzzy {
+        let threshold = query.threshold.unwrap_or(DEFAULT_FUZZY_THRESHOLD);
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(ApiError::bad_request("threshold must be between 0 and 1"));
+        }
+        return Ok(HttpResponse::Ok().json(database.fuzzy_search(user.user_id, q, threshold)));
+    }
+    Ok(HttpResponse::Ok().json(database.search(user.user_id, q)))
+}
+
+pub async fn count_tasks(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> impl Responder {
+    let (total, completed) = app_state.read_db().count_for(user.user_id);
+    HttpResponse::Ok().json(json!({
+        "total": total,
+        "completed": completed,
+        "active": total - completed,
+    }))
+}
+
+// Both columns of a board in one call.
+pub async fn grouped_tasks(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> impl Responder {
+    HttpResponse::Ok().json(app_state.read_db().grouped(user.user_id))
+}
+
+pub async fn delete_task(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    id: web::Path<u64>,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let id = id.into_inner();
+    let mut database = app_state.write_db();
+    match database.get(&id) {
+        None => return Err(ApiError::not_found()),
+        Some(task) if task.owner_id != user.user_id => return Err(ApiError::forbidden()),
+        Some(_) => {}
+    }
+    database.delete(&id);
+    app_state.mark_dirty();
+    app_state.publish(TaskEventKind::Deleted, user.user_id, id);
+    app_state.audit(AuditEvent::new(user.user_id, AuditAction::DeleteTask, id));
+    app_state
+        .metrics
+        .tasks_deleted
+        .fetch_add(1, Ordering::Relaxed);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn restore_task(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    id: web::Path<u64>,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let id = id.into_inner();
+    let mut database = app_state.write_db();
+    match database.get_including_deleted(&id) {
+        None => return Err(ApiError::not_found()),
+        Some(task) if task.owner_id != user.user_id => return Err(ApiError::forbidden()),
+        // Back in the live list it counts like a new task.
+        Some(task) if task.deleted_at.is_some() => {
+            app_state.check_task_quota(&database, user.user_id, 1)?
+        }
+        Some(_) => {}
+    }
+    // Restoring a task that isn't in the trash is a no-op.
+    if database.restore(&id) {
+        app_state.mark_dirty();
+        // Back in the live list, which to a subscriber looks the same as a new task.
+        app_state.publish(TaskEventKind::Created, user.user_id, id);
+        app_state.audit(AuditEvent::new(user.user_id, AuditAction::RestoreTask, id));
+    }
+    Ok(HttpResponse::Ok().json(database.get(&id)))
+}
+
+// Kept for older clients, which send the id in the body; prefer `PUT /task/{id}`.
+pub async fn update_task(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    task: web::Json<Task>,
+) -> Result<HttpResponse, ApiError> {
+    let task = replace_task(&app_state, &user, task.into_inner())?;
+    Ok(HttpResponse::Ok().json(task))
+}
+
+// The path id is the one that counts. The body may leave its id out, but one that disagrees
+// is more likely a client bug than something to silently override.
+pub async fn replace_task_by_id(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    id: web::Path<u64>,
+    task: web::Json<Task>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let mut task = task.into_inner();
+    // Ids start at 1, so 0 is what a missing one deserializes to.
+    if task.id != 0 && task.id != id {
+        let mut errors = ValidationErrors::new();
+        errors.add(
+            "id",
+            ValidationError::new("mismatch")
+                .with_message(format!("Body id {} does not match path id {id}", task.id).into()),
+        );
+        return Err(ApiError::invalid_fields("invalid_task", &errors));
+    }
+    task.id = id;
+    let task = replace_task(&app_state, &user, task)?;
+    Ok(HttpResponse::Ok().json(task))
+}
+
+fn replace_task(
+    app_state: &AppState,
+    user: &AuthenticatedUser,
+    mut task: Task,
+) -> Result<Task, ApiError> {
+    app_state.check_writable()?;
+    normalize_task(&mut task);
+    validate(&task)?;
+    let mut database = app_state.write_db();
+    let existing = match database.get(&task.id) {
+        None => return Err(ApiError::not_found()),
+        Some(existing) if existing.owner_id != user.user_id => return Err(ApiError::forbidden()),
+        Some(existing) if existing.version != task.version => {
+            return Err(version_mismatch(existing.version))
+        }
+        Some(existing) => existing,
+    };
+    task.owner_id = user.user_id;
+    task.created_at = existing.created_at;
+    task.deleted_at = None;
+    task.attachments = existing.attachments.clone();
+    database.check_list(&task)?;
+    database.check_dependencies(&task, Some(existing))?;
+    let just_completed = task.completed && !existing.completed;
+    task.touch();
+    database.update(task.clone());
+    app_state.mark_dirty();
+    app_state.publish(TaskEventKind::Updated, task.owner_id, task.id);
+    if just_completed {
+        app_state.task_completed(&database, &task);
+    }
+    app_state.audit(AuditEvent::new(
+        user.user_id,
+        AuditAction::UpdateTask,
+        task.id,
+    ));
+    Ok(task)
+}
+
+pub async fn patch_task(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    id: web::Path<u64>,
+    patch: web::Json<TaskPatch>,
+) -> Result<HttpResponse, ApiError> {
+    let task = apply_patch(&app_state, &user, id.into_inner(), patch.into_inner())?;
+    Ok(HttpResponse::Ok().json(task))
+}
+
+pub async fn complete_task(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    id: web::Path<u64>,
+) -> Result<HttpResponse, ApiError> {
+    let task = apply_patch(
+        &app_state,
+        &user,
+        id.into_inner(),
+        TaskPatch::completed(true),
+    )?;
+    Ok(HttpResponse::Ok().json(task))
+}
+
+pub async fn uncomplete_task(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    id: web::Path<u64>,
+) -> Result<HttpResponse, ApiError> {
+    let task = apply_patch(
+        &app_state,
+        &user,
+        id.into_inner(),
+        TaskPatch::completed(false),
+    )?;
+    Ok(HttpResponse::Ok().json(task))
+}
+
+// The shared update path behind PATCH and the complete/uncomplete shortcuts.
+fn apply_patch(
+    app_state: &AppState,
+    user: &AuthenticatedUser,
+    id: u64,
+    patch: TaskPatch,
+) -> Result<Task, ApiError> {
+    app_state.check_writable()?;
+    let mut database = app_state.write_db();
+    let existing = match database.get(&id) {
+        None => return Err(ApiError::not_found()),
+        Some(task) if task.owner_id != user.user_id => return Err(ApiError::forbidden()),
+        Some(task) if patch.version.is_some_and(|version| version != task.version) => {
+            return Err(version_mismatch(task.version))
+        }
+        Some(task) => task,
+    };
+    let mut task = existing.clone();
+    patch.apply(&mut task);
+    normalize_task(&mut task);
+    validate(&task)?;
+    database.check_list(&task)?;
+    database.check_dependencies(&task, Some(existing))?;
+    let just_completed = task.completed && !existing.completed;
+    task.touch();
+    database.update(task.clone());
+    app_state.mark_dirty();
+    app_state.publish(TaskEventKind::Updated, task.owner_id, task.id);
+    if just_completed {
+        app_state.task_completed(&database, &task);
+    }
+    app_state.audit(AuditEvent::new(
+        user.user_id,
+        AuditAction::UpdateTask,
+        task.id,
+    ));
+    Ok(task)
+}
@@ -0,0 +1,346 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use validator::Validate;
+
+use super::{Pagination, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::audit::{AuditAction, AuditEvent};
+use crate::auth::{
+    check_password_strength, hash_password, verify_password, verify_unknown_user, AdminUser,
+    AuthenticatedUser,
+};
+use crate::error::ApiError;
+use crate::events::TaskEventKind;
+use crate::models::{username_rule, Role, User, UserResponse};
+use crate::state::AppState;
+use crate::webhook::{check_webhook_target, validate_webhook_url};
+
+#[derive(Deserialize, Validate)]
+pub struct RegisterRequest {
+    pub id: u64,
+    #[validate(custom(function = username_rule))]
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    old_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountRequest {
+    password: String,
+}
+
+// A null `url` removes the webhook.
+#[derive(Deserialize)]
+pub struct WebhookRequest {
+    url: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct UserPage {
+    users: Vec<UserResponse>,
+    total: usize,
+    limit: usize,
+    offset: usize,
+}
+
+// Requests without a peer address (only possible in tests) are not limited.
+fn check_auth_rate_limit(app_state: &AppState, req: &HttpRequest) -> Result<(), ApiError> {
+    match req.peer_addr() {
+        Some(addr) => app_state
+            .auth_rate_limiter
+            .check(addr.ip())
+            .map_err(ApiError::too_many_requests),
+        None => Ok(()),
+    }
+}
+
+pub async fn register(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    request: web::Json<RegisterRequest>,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    check_auth_rate_limit(&app_state, &req)?;
+    let mut request = request.into_inner();
+    request.username = request.username.trim().to_string();
+    request
+        .validate()
+        .map_err(|errors| ApiError::invalid_fields("invalid_username", &errors))?;
+    let username = request.username;
+    check_password_strength(&request.password, app_state.min_password_len)?;
+    let password_hash = hash_password(&request.password).map_err(ApiError::internal)?;
+    // Check and insert under the same write lock so two concurrent registrations can't
+    // both claim the same name.
+    let mut database = app_state.write_db();
+    if database.get_user_by_name(&username).is_some() {
+        return Err(ApiError::conflict("Username is already taken"));
+    }
+    // Ids of deleted accounts stay taken; see `Database::retired_user_ids`.
+    if database.get_user(&request.id).is_some() || database.retired_user_ids.contains(&request.id) {
+        return Err(ApiError::conflict("User id is already taken"));
+    }
+    let user_id = request.id;
+    let role = if app_state.is_admin_username(&username) {
+        Role::Admin
+    } else {
+        Role::User
+    };
+    let user = User {
+        id: user_id,
+        username,
+        password_hash,
+        role,
+        webhook_url: None,
+    };
+    database.insert_user(user);
+    app_state.mark_dirty();
+    // Registering is the one change without a signed-in actor; the new user stands in.
+    app_state.audit(AuditEvent::new(user_id, AuditAction::Register, user_id));
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn login(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    request: web::Json<LoginRequest>,
+) -> Result<HttpResponse, ApiError> {
+    check_auth_rate_limit(&app_state, &req)?;
+    // Copied out so the slow verification doesn't hold up writers.
+    let stored_user = app_state
+        .read_db()
+        .get_user_by_name(&request.username)
+        .map(|user| (user.id, user.role, user.password_hash.clone()));
+    let verified = match &stored_user {
+        Some((_, _, password_hash)) => verify_password(&request.password, password_hash),
+        None => {
+            verify_unknown_user(&request.password);
+            false
+        }
+    };
+    match stored_user {
+        Some((user_id, role, _)) if verified => Ok(HttpResponse::Ok().json(json!({
+            "token": app_state.keyring.issue(user_id, role),
+            "refresh_token": app_state.issue_refresh_token(user_id),
+        }))),
+        _ => Err(ApiError::unauthorized("Invalid Username or Password")),
+    }
+}
+
+// Trades a refresh token for a new access token and a new refresh token; the old one is
+// spent either way. The new token carries the current role, although requests are checked
+// against the stored one anyway.
+pub async fn refresh(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    request: web::Json<RefreshRequest>,
+) -> Result<HttpResponse, ApiError> {
+    check_auth_rate_limit(&app_state, &req)?;
+    let invalid = || ApiError::unauthorized("Invalid or expired refresh token");
+    let user_id = app_state
+        .redeem_refresh_token(&request.refresh_token)
+        .ok_or_else(invalid)?;
+    let role = match app_state.read_db().get_user(&user_id) {
+        Some(stored_user) => stored_user.role,
+        None => return Err(invalid()),
+    };
+    Ok(HttpResponse::Ok().json(json!({
+        "token": app_state.keyring.issue(user_id, role),
+        "refresh_token": app_state.issue_refresh_token(user_id),
+    })))
+}
+
+pub async fn me(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    match app_state.read_db().get_user(&user.user_id) {
+        Some(stored_user) => Ok(HttpResponse::Ok().json(UserResponse::from(stored_user))),
+        // The token outlived its user.
+        None => Err(ApiError::unauthorized("Missing or invalid token")),
+    }
+}
+
+// Checks the signed-in user's password and returns the hash it was checked against, for the
+// caller to compare once it holds the write lock. The lock isn't held while verifying, which
+// is slow.
+fn verify_current_password(
+    app_state: &AppState,
+    user_id: u64,
+    password: &str,
+) -> Result<String, ApiError> {
+    let password_hash = app_state
+        .read_db()
+        .get_user(&user_id)
+        .map(|user| user.password_hash.clone())
+        .ok_or_else(ApiError::not_found)?;
+    if !verify_password(password, &password_hash) {
+        return Err(ApiError::unauthorized("Invalid password"));
+    }
+    Ok(password_hash)
+}
+
+// Deletes the caller's account and every task they own in one write and one save. The password
+// is asked for again so a stolen token alone can't wipe an account.
+pub async fn delete_me(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    request: web::Json<DeleteAccountRequest>,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let verified_hash = verify_current_password(&app_state, user.user_id, &request.password)?;
+    let mut database = app_state.write_db();
+    match database.get_user(&user.user_id) {
+        // Verified against a password that has since been changed.
+        Some(stored_user) if stored_user.password_hash != verified_hash => {
+            return Err(ApiError::conflict("Password was changed concurrently"));
+        }
+        Some(_) => {}
+        None => return Err(ApiError::not_found()),
+    }
+    let removed = database
+        .remove_user(&user.user_id)
+        .ok_or_else(ApiError::not_found)?;
+    drop(database);
+    app_state.mark_dirty();
+    app_state.revoke_token(user.token_id, user.token_expires_at);
+    app_state.revoke_refresh_tokens(user.user_id);
+    for task_id in removed {
+        app_state.publish(TaskEventKind::Deleted, user.user_id, task_id);
+    }
+    app_state.audit(AuditEvent::new(
+        user.user_id,
+        AuditAction::DeleteUser,
+        user.user_id,
+    ));
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn set_webhook(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    request: web::Json<WebhookRequest>,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let url = request.into_inner().url.map(|url| url.trim().to_string());
+    if let Some(url) = &url {
+        validate_webhook_url(url)?;
+        check_webhook_target(url, &app_state.webhook_allowed_hosts).await?;
+    }
+    let mut database = app_state.write_db();
+    let stored_user = database
+        .get_user_mut(&user.user_id)
+        .ok_or_else(ApiError::not_found)?;
+    stored_user.webhook_url = url;
+    let response = json!({ "webhook_url": stored_user.webhook_url });
+    app_state.mark_dirty();
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn list_users(
+    app_state: web::Data<AppState>,
+    _admin: AdminUser,
+    page: web::Query<Pagination>,
+) -> impl Responder {
+    let limit = page.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let offset = page.offset.unwrap_or(0);
+
+    let database = app_state.read_db();
+    let users = database.get_users();
+    HttpResponse::Ok().json(UserPage {
+        total: users.len(),
+        users: users
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(UserResponse::from)
+            .collect(),
+        limit,
+        offset,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct RoleUpdate {
+    pub role: Role,
+}
+
+pub async fn set_user_role(
+    app_state: web::Data<AppState>,
+    AdminUser(admin): AdminUser,
+    user_id: web::Path<u64>,
+    update: web::Json<RoleUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    let user_id = user_id.into_inner();
+    // Otherwise the last admin could demote themselves and leave nobody able to promote.
+    if user_id == admin.user_id {
+        return Err(ApiError::bad_request("You cannot change your own role"));
+    }
+    let mut database = app_state.write_db();
+    let stored_user = database
+        .get_user_mut(&user_id)
+        .ok_or_else(ApiError::not_found)?;
+    stored_user.role = update.role;
+    let response = UserResponse::from(&*stored_user);
+    app_state.mark_dirty();
+    app_state.audit(AuditEvent::new(
+        admin.user_id,
+        AuditAction::SetRole,
+        user_id,
+    ));
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn change_password(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    request: web::Json<ChangePasswordRequest>,
+) -> Result<HttpResponse, ApiError> {
+    app_state.check_writable()?;
+    check_password_strength(&request.new_password, app_state.min_password_len)?;
+    let verified_hash = verify_current_password(&app_state, user.user_id, &request.old_password)?;
+    // Hashing is slow, so it happens outside the lock.
+    let password_hash = hash_password(&request.new_password).map_err(ApiError::internal)?;
+    let mut database = app_state.write_db();
+    match database.get_user_mut(&user.user_id) {
+        // A concurrent change won the race; the old password no longer holds.
+        Some(stored_user) if stored_user.password_hash != verified_hash => {
+            Err(ApiError::conflict("Password was changed concurrently"))
+        }
+        Some(stored_user) => {
+            stored_user.password_hash = password_hash;
+            app_state.mark_dirty();
+            app_state.revoke_refresh_tokens(user.user_id);
+            app_state.audit(AuditEvent::new(
+                user.user_id,
+                AuditAction::ChangePassword,
+                user.user_id,
+            ));
+            Ok(HttpResponse::NoContent().finish())
+        }
+        None => Err(ApiError::not_found()),
+    }
+}
+
+// Ends every session of the user: refresh tokens issued on other devices are revoked too.
+pub async fn logout(app_state: web::Data<AppState>, user: AuthenticatedUser) -> impl Responder {
+    app_state.revoke_token(user.token_id, user.token_expires_at);
+    app_state.revoke_refresh_tokens(user.user_id);
+    HttpResponse::NoContent().finish()
+}
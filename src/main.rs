@@ -1,172 +1,234 @@
-use actix_cors::Cors;
-use actix_web::{http::header, web, App, HttpResponse, HttpServer, Responder};
-use serde::{Deserialize, Serialize};
+mod attachments;
+mod audit;
+mod auth;
+mod backup;
+mod config;
+mod error;
+mod events;
+mod handlers;
+mod metrics;
+mod models;
+mod notify;
+mod rate_limit;
+mod state;
+mod storage;
+mod webhook;
+
+use actix_web::dev::Service;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Compress;
+use actix_web::{web, App, HttpServer};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+use std::env;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::attachments::{DEFAULT_ATTACHMENTS_DIR, DEFAULT_MAX_ATTACHMENT_BYTES};
+use crate::audit::{write_audit_log, DEFAULT_AUDIT_LOG_PATH};
+use crate::auth::{
+    Keyring, DEFAULT_MIN_PASSWORD_LEN, DEFAULT_REFRESH_TOKEN_DAYS, DEFAULT_TOKEN_MINUTES,
+};
+use crate::backup::{
+    run_backups, DEFAULT_BACKUP_DIR, DEFAULT_BACKUP_INTERVAL_MINUTES, DEFAULT_BACKUP_KEEP,
+};
+use crate::config::{
+    admin_usernames, build_cors, cors_origins, env_nonzero, env_opt, env_or, json_config,
+    tls_config, webhook_allowed_hosts, DEFAULT_AUTH_RATE_BURST, DEFAULT_AUTH_RATE_PER_MINUTE,
+    DEFAULT_BIND_ADDR, DEFAULT_IMPORT_LIMIT_BYTES, DEFAULT_JSON_LIMIT_BYTES,
+    DEFAULT_LIST_MAX_AGE_SECS, DEFAULT_MAX_CONCURRENT_WRITES, DEFAULT_MAX_TASKS_PER_USER,
+    DEFAULT_REQUEST_TIMEOUT_SECS, DEFAULT_SLOW_REQUEST_MS, DEFAULT_TRASH_RETENTION_DAYS,
+    REQUEST_ID_HEADER, SHUTDOWN_TIMEOUT_SECS,
+};
+use crate::error::{catch_panics, enforce_timeout};
+use crate::handlers::{api, route_not_found};
+use crate::metrics::track_requests;
+use crate::models::Role;
+use crate::notify::{run_notifier, WebhookNotifier};
+use crate::rate_limit::{limit_writes, RateLimiter};
+use crate::state::{persist_changes, run_housekeeping, AppState};
+use crate::storage::{load_database, storage_from_env};
+use crate::webhook::{http_client, validate_webhook_url};
 
-use std::collections::HashMap;
-use std::fs;
-use std::io::Write;
-use std::sync::Mutex;
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Task {
-    id: u64,
-    name: String,
-    completed: bool,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct User {
-    id: u64,
-    username: String,
-    password: String,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Database {
-    tasks: HashMap<u64, Task>,
-    users: HashMap<u64, User>,
-}
-
-impl Database {
-    fn new() -> Self {
-        Self {
-            tasks: HashMap::new(),
-            users: HashMap::new(),
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    dotenv::dotenv().ok();
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+    let mut keyring = Keyring::from_env()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string()))?;
+    keyring.token_lifetime =
+        Duration::from_secs(env_nonzero("TR_JWT_EXP_MINUTES", DEFAULT_TOKEN_MINUTES)? * 60);
+
+    let storage = storage_from_env().await?;
+    let mut db = load_database(storage.as_ref(), env_or("TR_STRICT_LOAD", true)?).await?;
+    db.sync_next_id();
+
+    let (dirty_tx, dirty_rx) = mpsc::channel(1);
+    let auth_rate_limiter = RateLimiter::new(
+        env_or("TR_AUTH_RATE_BURST", DEFAULT_AUTH_RATE_BURST)?,
+        env_or("TR_AUTH_RATE_PER_MINUTE", DEFAULT_AUTH_RATE_PER_MINUTE)?,
+    );
+    let http_client = http_client().map_err(std::io::Error::other)?;
+    let mut app_state = AppState::new(
+        db,
+        storage,
+        dirty_tx,
+        auth_rate_limiter,
+        http_client,
+        keyring,
+    );
+    app_state.min_password_len = env_nonzero("TR_MIN_PASSWORD_LEN", DEFAULT_MIN_PASSWORD_LEN)?;
+    let refresh_days = env_nonzero("TR_REFRESH_EXP_DAYS", DEFAULT_REFRESH_TOKEN_DAYS)?;
+    app_state.refresh_token_lifetime = Duration::from_secs(refresh_days * 24 * 60 * 60);
+    app_state.max_tasks_per_user = env_or("TR_MAX_TASKS_PER_USER", DEFAULT_MAX_TASKS_PER_USER)?;
+    app_state.list_max_age_secs = env_or("TR_LIST_MAX_AGE_SECS", DEFAULT_LIST_MAX_AGE_SECS)?;
+    let max_writes = match env_or("TR_MAX_CONCURRENT_WRITES", DEFAULT_MAX_CONCURRENT_WRITES)? {
+        0 => Semaphore::MAX_PERMITS,
+        max_writes => max_writes,
+    };
+    app_state.write_permits = Arc::new(Semaphore::new(max_writes));
+    app_state.webhook_allowed_hosts = webhook_allowed_hosts();
+    app_state.admin_usernames = admin_usernames();
+    for user in app_state.write_db().users.values_mut() {
+        if app_state.is_admin_username(&user.username) && user.role != Role::Admin {
+            user.role = Role::Admin;
+            tracing::info!(user = user.id, "promoted to admin by TR_ADMIN_USERNAMES");
         }
     }
-    // CRUD DATA
-    fn insert(&mut self, task: Task) {
-        self.tasks.insert(task.id, task);
-    }
-
-    fn get(&self, id: &u64) -> Option<&Task> {
-        self.tasks.get(id)
-    }
-
-    fn get_all(&self) -> Vec<&Task> {
-        self.tasks.values().collect()
-    }
-
-    fn delete(&mut self, id: &u64) {
-        self.tasks.remove(id);
-    }
-
-    fn update(&mut self, task: Task) {
-        self.tasks.insert(task.id, task);
-    }
-
-    // USER DATA RELATED FUNCTIONS
-    fn insert_user(&mut self, user: User) {
-        self.users.insert(user.id, user);
-    }
-
-    fn get_user_by_name(&self, username: &str) -> Option<&User> {
-        self.users.values().find(|user| user.username == username)
+    if let Some(url) = env_opt::<String>("TR_NOTIFY_URL")? {
+        validate_webhook_url(&url).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid TR_NOTIFY_URL: {err}"),
+            )
+        })?;
+        app_state.notifier = Box::new(WebhookNotifier::new(app_state.http_client.clone(), url));
     }
-
-    // Database saving
-    fn save_to_file(&self) -> std::io::Result<()> {
-        let data: String = serde_json::to_string(&self)?;
-        let mut file = fs::File::create("database.json")?;
-        file.write_all(data.as_bytes())?;
-        Ok(())
+    app_state.attachments_dir = env::var("TR_ATTACHMENTS_DIR")
+        .unwrap_or_else(|_| DEFAULT_ATTACHMENTS_DIR.into())
+        .into();
+    app_state.max_attachment_bytes =
+        env_or("TR_MAX_ATTACHMENT_BYTES", DEFAULT_MAX_ATTACHMENT_BYTES)?;
+    app_state.import_limit_bytes = env_or("TR_IMPORT_LIMIT_BYTES", DEFAULT_IMPORT_LIMIT_BYTES)?;
+    let trash_retention_days = env_or("TR_TRASH_RETENTION_DAYS", DEFAULT_TRASH_RETENTION_DAYS)?;
+    app_state.trash_retention = chrono::Duration::days(trash_retention_days.into());
+    app_state.set_read_only(env_or("TR_READONLY", false)?);
+    let persist = env_or("TR_PERSIST", true)?;
+    if persist {
+        let path = env::var("TR_AUDIT_LOG").unwrap_or_else(|_| DEFAULT_AUDIT_LOG_PATH.into());
+        let (audit_tx, audit_rx) = mpsc::unbounded_channel();
+        app_state.audit_log = Some(audit_tx);
+        actix_web::rt::spawn(write_audit_log(path.into(), audit_rx));
     }
-
-    fn load_from_file() -> std::io::Result<Self> {
-        let file_contents = fs::read_to_string("database.json")?;
-        let db: Database = serde_json::from_str(&file_contents)?;
-        Ok(db)
+    #[cfg(feature = "demo")]
+    tracing::warn!("demo build: /admin/seed and /admin/reset can wipe the database");
+    let data = web::Data::new(app_state);
+    actix_web::rt::spawn(persist_changes(data.clone(), dirty_rx));
+    actix_web::rt::spawn(run_notifier(data.clone(), data.events.subscribe()));
+    actix_web::rt::spawn(run_housekeeping(data.clone()));
+    // An interval of 0 turns backups off.
+    let backup_minutes = env_or(
+        "TR_BACKUP_INTERVAL_MINUTES",
+        DEFAULT_BACKUP_INTERVAL_MINUTES,
+    )?;
+    if persist && backup_minutes > 0 {
+        let dir = env::var("TR_BACKUP_DIR").unwrap_or_else(|_| DEFAULT_BACKUP_DIR.into());
+        // Keeping none would delete each backup as soon as it was written.
+        let keep = env_nonzero("TR_BACKUP_KEEP", DEFAULT_BACKUP_KEEP)?;
+        actix_web::rt::spawn(run_backups(
+            data.clone(),
+            dir.into(),
+            Duration::from_secs(backup_minutes * 60),
+            keep,
+        ));
     }
-}
 
-struct AppState {
-    db: Mutex<Database>,
-}
+    let metrics_enabled = env_or("TR_METRICS_ENABLED", false)?;
 
-async fn create_task(app_state: web::Data<AppState>, task: web::Json<Task>) -> impl Responder {
-    let mut database = app_state.db.lock().unwrap();
-    database.insert(task.into_inner());
-    let _ = database.save_to_file();
-    HttpResponse::Ok().finish()
-}
+    let cors_origins = cors_origins()?;
+    let json_limit = env_or("TR_JSON_LIMIT_BYTES", DEFAULT_JSON_LIMIT_BYTES)?;
+    let bind_addr = env_or("TR_BIND_ADDR", DEFAULT_BIND_ADDR)?;
+    // Defaults to one worker per CPU; small containers may want fewer.
+    let workers: Option<NonZeroUsize> = env_opt("TR_WORKERS")?;
+    let request_timeout = match env_or("TR_REQUEST_TIMEOUT_SECS", DEFAULT_REQUEST_TIMEOUT_SECS)? {
+        0 => Duration::MAX,
+        secs => Duration::from_secs(secs),
+    };
 
-async fn get_task(app_state: web::Data<AppState>, task_id: web::Path<u64>) -> impl Responder {
-    let database = app_state.db.lock().unwrap();
+    // 0 logs nothing as slow.
+    let slow_request = match env_or("TR_SLOW_REQUEST_MS", DEFAULT_SLOW_REQUEST_MS)? {
+        0 => Duration::MAX,
+        millis => Duration::from_millis(millis),
+    };
 
-    match database.get(&task_id.into_inner()) {
-        Some(task) => HttpResponse::Ok().json(task),
-        None => HttpResponse::NotFound().finish(),
+    let app_data = data.clone();
+    let mut server = HttpServer::new(move || {
+        App::new()
+            .wrap_fn(catch_panics)
+            .wrap_fn(move |req, srv| enforce_timeout(req, srv, request_timeout))
+            .wrap_fn(limit_writes)
+            .wrap_fn(move |req, srv| track_requests(req, srv, slow_request))
+            .wrap_fn(|req, srv| {
+                let request_id = Uuid::new_v4().to_string();
+                let span = tracing::info_span!(
+                    "request",
+                    request_id = %request_id,
+                    method = %req.method(),
+                    path = %req.path(),
+                );
+                let started = Instant::now();
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    tracing::info!(
+                        status = response.status().as_u16(),
+                        latency_ms = started.elapsed().as_secs_f64() * 1000.0,
+                        "request handled"
+                    );
+                    if let Ok(value) = HeaderValue::from_str(&request_id) {
+                        response
+                            .headers_mut()
+                            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                    }
+                    Ok(response)
+                }
+                .instrument(span)
+            })
+            // Compresses per `Accept-Encoding`; streamed bodies are encoded chunk by chunk.
+            .wrap(Compress::default())
+            .wrap(build_cors(&cors_origins))
+            .app_data(app_data.clone())
+            .app_data(json_config(json_limit))
+            .default_service(web::to(route_not_found))
+            .configure(|cfg| api(cfg, metrics_enabled))
+    })
+    .shutdown_timeout(SHUTDOWN_TIMEOUT_SECS);
+    if let Some(workers) = workers {
+        server = server.workers(workers.get());
     }
-}
-
-async fn get_all_tasks(app_state: web::Data<AppState>) -> impl Responder {
-    let database = app_state.db.lock().unwrap();
-    HttpResponse::Ok().json(database.get_all())
-}
-
-async fn delete_task(app_state: web::Data<AppState>, id: web::Path<u64>) -> impl Responder {
-    let mut database = app_state.db.lock().unwrap();
-    database.delete(&id.into_inner());
-    let _ = database.save_to_file();
-    HttpResponse::Ok()
-}
-
-async fn update_task(app_state: web::Data<AppState>, task: web::Json<Task>) -> impl Responder {
-    let mut database = app_state.db.lock().unwrap();
-    database.update(task.into_inner());
-    let _ = database.save_to_file();
-    HttpResponse::Ok().finish()
-}
-
-async fn register(app_state: web::Data<AppState>, user: web::Json<User>) -> impl Responder {
-    let mut database = app_state.db.lock().unwrap();
-    database.insert_user(user.into_inner());
-    let _ = database.save_to_file();
-    HttpResponse::Ok()
-}
-
-async fn login(app_state: web::Data<AppState>, user: web::Json<User>) -> impl Responder {
-    let database = app_state.db.lock().unwrap();
-    match database.get_user_by_name(&user.username) {
-        Some(stored_user) if user.username == stored_user.username => {
-            HttpResponse::Ok().body("User Logged in!")
+    server = match tls_config()? {
+        Some(tls) => {
+            tracing::info!("serving HTTPS");
+            server.bind_rustls_0_23(bind_addr, tls)?
         }
-        _ => HttpResponse::BadRequest().body("Invalid Username or Password"),
-    }
-}
-
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let db = match Database::load_from_file() {
-        Ok(db) => db,
-        Err(_) => Database::new(),
+        None => server.bind(bind_addr)?,
     };
+    server.run().await?;
 
-    let data = web::Data::new(AppState { db: Mutex::new(db) });
-
-    HttpServer::new(move || {
-        App::new()
-            .wrap(
-                Cors::permissive()
-                    .allowed_origin_fn(|origin, req_head| {
-                        origin.as_bytes().starts_with(b"http://localhost") || origin == "null"
-                    })
-                    .allowed_methods(vec!["GET", "POST", "DELETE", "PUT"])
-                    .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
-                    .allowed_header(header::CONTENT_TYPE)
-                    .supports_credentials()
-                    .max_age(3600),
-            )
-            .app_data(data.clone())
-            .route("/task", web::post().to(create_task))
-            .route("/tasks", web::get().to(get_all_tasks))
-            .route("/task", web::put().to(update_task))
-            .route("/task/{id}", web::get().to(get_task))
-            .route("/task/{id}", web::delete().to(delete_task))
-            .route("/register", web::post().to(register))
-            .route("/login", web::post().to(login))
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    // On SIGINT/SIGTERM the server stops accepting connections and waits for in-flight
+    // requests before `run` returns, so nothing else is writing at this point.
+    data.save().await?;
+    tracing::info!("database flushed");
+    Ok(())
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,211 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::{web, Error};
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::state::AppState;
+
+// Upper bounds of the latency histogram buckets, in seconds; slower requests only show up
+// in the implicit `+Inf` bucket.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default, Clone)]
+pub struct RouteStats {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+    // Not cumulative: each request is counted in the first bucket it fits.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+}
+
+impl RouteStats {
+    fn record(&mut self, latency: Duration) {
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+        let secs = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+    }
+
+    // Requests at or under each bound, Prometheus style.
+    fn cumulative(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        LATENCY_BUCKETS
+            .iter()
+            .zip(self.buckets.iter().scan(0, |total, count| {
+                *total += count;
+                Some(*total)
+            }))
+            .map(|(bound, count)| (*bound, count))
+    }
+}
+
+// Hand-rolled Prometheus registry; rendered in text exposition format by `/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    // By method and route pattern, so `/task/1` and `/task/2` add up.
+    pub requests: Mutex<BTreeMap<(String, String), RouteStats>>,
+    pub tasks_created: AtomicU64,
+    pub tasks_deleted: AtomicU64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RouteSummary {
+    method: String,
+    route: String,
+    count: u64,
+    mean_ms: f64,
+    max_ms: f64,
+    // Cumulative: requests that took at most `le_ms`.
+    latency: Vec<LatencyBucket>,
+}
+
+#[derive(Serialize, Debug)]
+struct LatencyBucket {
+    le_ms: f64,
+    count: u64,
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl Metrics {
+    pub fn record_request(&self, method: &str, route: &str, latency: Duration) {
+        let mut requests = self.requests.lock().unwrap_or_else(PoisonError::into_inner);
+        requests
+            .entry((method.to_string(), route.to_string()))
+            .or_default()
+            .record(latency);
+    }
+
+    pub fn summary(&self) -> Vec<RouteSummary> {
+        let requests = self.requests.lock().unwrap_or_else(PoisonError::into_inner);
+        requests
+            .iter()
+            .map(|((method, route), stats)| RouteSummary {
+                method: method.clone(),
+                route: route.clone(),
+                count: stats.count,
+                mean_ms: millis(stats.total) / stats.count.max(1) as f64,
+                max_ms: millis(stats.max),
+                latency: stats
+                    .cumulative()
+                    .map(|(bound, count)| LatencyBucket {
+                        le_ms: bound * 1000.0,
+                        count,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    pub fn render(&self, tasks: usize, users: usize) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP tr_http_requests_total Requests received, by method and route.\n");
+        out.push_str("# TYPE tr_http_requests_total counter\n");
+        let requests = self.requests.lock().unwrap_or_else(PoisonError::into_inner);
+        for ((method, route), stats) in requests.iter() {
+            out.push_str(&format!(
+                "tr_http_requests_total{{method=\"{method}\",route=\"{route}\"}} {}\n",
+                stats.count
+            ));
+        }
+        out.push_str(
+            "# HELP tr_http_request_duration_seconds Time spent in handlers, by method and route.\n",
+        );
+        out.push_str("# TYPE tr_http_request_duration_seconds histogram\n");
+        for ((method, route), stats) in requests.iter() {
+            let labels = format!("method=\"{method}\",route=\"{route}\"");
+            for (bound, count) in stats.cumulative() {
+                out.push_str(&format!(
+                    "tr_http_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {count}\n"
+                ));
+            }
+            out.push_str(&format!(
+                "tr_http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}\n\
+                 tr_http_request_duration_seconds_sum{{{labels}}} {}\n\
+                 tr_http_request_duration_seconds_count{{{labels}}} {}\n",
+                stats.count,
+                stats.total.as_secs_f64(),
+                stats.count
+            ));
+        }
+        drop(requests);
+
+        let counters = [
+            (
+                "tr_tasks_created_total",
+                "Tasks created.",
+                &self.tasks_created,
+            ),
+            (
+                "tr_tasks_deleted_total",
+                "Tasks deleted.",
+                &self.tasks_deleted,
+            ),
+        ];
+        for (name, help, value) in counters {
+            out.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n",
+                value.load(Ordering::Relaxed)
+            ));
+        }
+
+        let gauges = [
+            ("tr_tasks", "Tasks currently stored.", tasks),
+            ("tr_users", "Users currently registered.", users),
+        ];
+        for (name, help, value) in gauges {
+            out.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+            ));
+        }
+        out
+    }
+}
+
+// Middleware timing every request into `Metrics` and logging the ones slower than
+// `slow_threshold` at WARN. Only the handler is timed, like `enforce_timeout` does, so a
+// long-lived event stream doesn't count as slow.
+pub fn track_requests<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+    slow_threshold: Duration,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let app_state = req.app_data::<web::Data<AppState>>().cloned();
+    let route = req.match_pattern().unwrap_or_else(|| "unmatched".into());
+    let method = req.method().clone();
+    let path = req.path().to_string();
+    let started = Instant::now();
+    let response = srv.call(req);
+    async move {
+        let response = response.await;
+        let latency = started.elapsed();
+        if let Some(app_state) = app_state {
+            app_state
+                .metrics
+                .record_request(method.as_str(), &route, latency);
+        }
+        if latency > slow_threshold {
+            tracing::warn!(
+                %method,
+                %path,
+                duration_ms = millis(latency),
+                "slow request"
+            );
+        }
+        response
+    }
+}
@@ -0,0 +1,1097 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Validate)]
+pub struct Task {
+    #[serde(default)]
+    pub id: u64,
+    #[validate(length(
+        min = 1,
+        max = MAX_TASK_NAME_LEN,
+        message = "Task name must be between 1 and 256 characters"
+    ))]
+    pub name: String,
+    pub completed: bool,
+    #[serde(default)]
+    pub owner_id: u64,
+    // Defaulting to now backfills tasks saved before timestamps existed.
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default = "Utc::now")]
+    updated_at: DateTime<Utc>,
+    // Set when the task is moved to the trash; it is purged for good once this is older than
+    // the retention period.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    // Bumped on every change. Writers send back the version they read, so a stale write is
+    // rejected instead of silently overwriting someone else's edit.
+    #[serde(default)]
+    pub version: u64,
+    #[serde(default)]
+    #[validate(
+        length(max = MAX_TAGS, message = "A task can have at most 20 tags"),
+        custom(function = validate_tag_lengths)
+    )]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    due_at: Option<DateTime<Utc>>,
+    // Unknown values fail deserialization; tasks saved before priorities existed are medium.
+    #[serde(default)]
+    pub priority: Priority,
+    // Only the latest occurrence carries this: once it is completed the housekeeping job
+    // creates the next one and moves the recurrence over to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence: Option<Recurrence>,
+    // Ids of tasks that have to be completed before this one can be.
+    #[serde(default)]
+    pub depends_on: Vec<u64>,
+    // Tasks saved before lists existed, or created without one, are in the inbox.
+    #[serde(default, skip_serializing_if = "is_inbox")]
+    pub list_id: u64,
+    // Managed through the attachment endpoints; whatever a client sends here is ignored.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+}
+
+// A file stored alongside a task; see `attachments`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    pub created_at: DateTime<Utc>,
+}
+
+// One flattened spreadsheet row per task; `csv` can't serialize the list fields, so tags and
+// dependencies are joined with `;`.
+#[derive(Serialize, Debug)]
+pub struct TaskCsvRow<'a> {
+    id: u64,
+    name: &'a str,
+    completed: bool,
+    priority: Priority,
+    tags: String,
+    depends_on: String,
+    list_id: u64,
+    due_at: Option<DateTime<Utc>>,
+    recurrence: Option<Recurrence>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl TaskCsvRow<'_> {
+    // The header `csv` writes along with the first row, for listings that have none. Keep it
+    // in step with the fields; the CSV listing test checks one against the other.
+    pub const HEADERS: [&'static str; 12] = [
+        "id",
+        "name",
+        "completed",
+        "priority",
+        "tags",
+        "depends_on",
+        "list_id",
+        "due_at",
+        "recurrence",
+        "created_at",
+        "updated_at",
+        "deleted_at",
+    ];
+}
+
+impl<'a> From<&'a Task> for TaskCsvRow<'a> {
+    fn from(task: &'a Task) -> Self {
+        let depends_on: Vec<String> = task.depends_on.iter().map(u64::to_string).collect();
+        Self {
+            id: task.id,
+            name: &task.name,
+            completed: task.completed,
+            priority: task.priority,
+            tags: task.tags.join(";"),
+            depends_on: depends_on.join(";"),
+            list_id: task.list_id,
+            due_at: task.due_at,
+            recurrence: task.recurrence,
+            created_at: task.created_at,
+            updated_at: task.updated_at,
+            deleted_at: task.deleted_at,
+        }
+    }
+}
+
+// Declared low to high so the derived ordering sorts by importance.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Recurrence {
+    Daily,
+    Weekly,
+}
+
+impl Recurrence {
+    fn period(self) -> Duration {
+        match self {
+            Recurrence::Daily => Duration::days(1),
+            Recurrence::Weekly => Duration::weeks(1),
+        }
+    }
+}
+
+impl Task {
+    pub fn is_overdue(&self, now: DateTime<Utc>) -> bool {
+        !self.completed && self.due_at.is_some_and(|due_at| due_at < now)
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|own| same_tag(own, tag))
+    }
+
+    pub fn touch(&mut self) {
+        self.updated_at = Utc::now();
+        self.version += 1;
+    }
+}
+
+// TASK VALIDATION
+// The rules live on `Task` as `validator` attributes; see `ApiError::invalid_fields` for how
+// failures reach the client. Priorities need no rule, unknown ones fail deserialization.
+const MAX_TASK_NAME_LEN: u64 = 256;
+const MAX_TAGS: u64 = 20;
+const MAX_TAG_LEN: usize = 32;
+
+fn validate_tag_lengths(tags: &[String]) -> Result<(), ValidationError> {
+    if tags.iter().any(|tag| tag.chars().count() > MAX_TAG_LEN) {
+        return Err(ValidationError::new("length")
+            .with_message(format!("Tags must be at most {MAX_TAG_LEN} characters").into()));
+    }
+    Ok(())
+}
+
+// Expects the name to have been trimmed already; see `normalize_task`.
+pub fn validate(task: &Task) -> Result<(), ValidationErrors> {
+    task.validate()
+}
+
+// Edit distance counting single-character insertions, deletions and substitutions.
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, a_char) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+fn same_tag(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+// Tags are trimmed, blank ones dropped, and duplicates that differ only in case collapsed
+// into the first spelling.
+pub fn normalize_task(task: &mut Task) {
+    task.name = task.name.trim().to_string();
+    let mut tags: Vec<String> = Vec::with_capacity(task.tags.len());
+    for tag in task.tags.drain(..) {
+        let tag = tag.trim();
+        if !tag.is_empty() && !tags.iter().any(|seen| same_tag(seen, tag)) {
+            tags.push(tag.to_string());
+        }
+    }
+    task.tags = tags;
+    task.depends_on.sort_unstable();
+    task.depends_on.dedup();
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DependencyError {
+    // Doesn't exist, is in the trash or belongs to someone else.
+    Unknown(u64),
+    Cycle,
+    Unfinished(u64),
+}
+
+impl fmt::Display for DependencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DependencyError::Unknown(id) => write!(f, "Task {id} can't be depended on"),
+            DependencyError::Cycle => write!(f, "Dependencies must not form a cycle"),
+            DependencyError::Unfinished(id) => {
+                write!(f, "Task {id} has to be completed first")
+            }
+        }
+    }
+}
+
+// Partial update body for PATCH; omitted fields are left as they are. `version` is optional
+// here, but when given it must match like it does for PUT.
+#[derive(Deserialize, Debug)]
+pub struct TaskPatch {
+    pub name: Option<String>,
+    pub completed: Option<bool>,
+    pub tags: Option<Vec<String>>,
+    pub priority: Option<Priority>,
+    // `null` clears the due date, while leaving the field out keeps it.
+    #[serde(default, deserialize_with = "present")]
+    due_at: Option<Option<DateTime<Utc>>>,
+    #[serde(default, deserialize_with = "present")]
+    recurrence: Option<Option<Recurrence>>,
+    pub depends_on: Option<Vec<u64>>,
+    pub list_id: Option<u64>,
+    pub version: Option<u64>,
+}
+
+// Distinguishes a field sent as `null` (`Some(None)`) from one that was omitted (`None`).
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl TaskPatch {
+    pub fn completed(completed: bool) -> Self {
+        Self {
+            name: None,
+            completed: Some(completed),
+            tags: None,
+            priority: None,
+            due_at: None,
+            recurrence: None,
+            depends_on: None,
+            list_id: None,
+            version: None,
+        }
+    }
+
+    pub fn apply(self, task: &mut Task) {
+        if let Some(name) = self.name {
+            task.name = name;
+        }
+        if let Some(completed) = self.completed {
+            task.completed = completed;
+        }
+        if let Some(tags) = self.tags {
+            task.tags = tags;
+        }
+        if let Some(priority) = self.priority {
+            task.priority = priority;
+        }
+        if let Some(due_at) = self.due_at {
+            task.due_at = due_at;
+        }
+        if let Some(recurrence) = self.recurrence {
+            task.recurrence = recurrence;
+        }
+        if let Some(depends_on) = self.depends_on {
+            task.depends_on = depends_on;
+        }
+        if let Some(list_id) = self.list_id {
+            task.list_id = list_id;
+        }
+    }
+}
+
+// TASK LISTS
+// Every user has an implicit inbox with id 0 that can't be renamed or deleted; their own
+// lists take ids from 1 up, from one counter shared by all users (`next_list_id`).
+pub const INBOX_LIST_ID: u64 = 0;
+pub const INBOX_NAME: &str = "Inbox";
+const MAX_LIST_NAME_LEN: usize = 64;
+
+fn is_inbox(list_id: &u64) -> bool {
+    *list_id == INBOX_LIST_ID
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskList {
+    pub id: u64,
+    pub owner_id: u64,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ListError {
+    InvalidName,
+    NameTaken,
+    // Doesn't exist or belongs to someone else.
+    Unknown(u64),
+    NotEmpty(usize),
+}
+
+impl fmt::Display for ListError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListError::InvalidName => write!(
+                f,
+                "List name must be between 1 and {MAX_LIST_NAME_LEN} characters"
+            ),
+            ListError::NameTaken => write!(f, "A list with this name already exists"),
+            ListError::Unknown(id) => write!(f, "List {id} does not exist"),
+            ListError::NotEmpty(tasks) => write!(
+                f,
+                "List still has {tasks} tasks; pass cascade=true to delete them with it"
+            ),
+        }
+    }
+}
+
+// `password_hash` has to stay serializable because `Database` persists users through serde;
+// responses go through `UserResponse` instead, and `Debug` redacts it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct User {
+    pub id: u64,
+    pub username: String,
+    pub password_hash: String,
+    // Users saved before roles existed are regular users.
+    #[serde(default)]
+    pub role: Role,
+    // Called when one of the user's tasks is completed; see `webhook`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("username", &self.username)
+            .field("password_hash", &"<redacted>")
+            .field("role", &self.role)
+            .field("webhook_url", &self.webhook_url)
+            .finish()
+    }
+}
+
+const MIN_USERNAME_LEN: usize = 3;
+const MAX_USERNAME_LEN: usize = 32;
+
+#[derive(Debug, PartialEq)]
+pub enum UsernameError {
+    TooShort,
+    TooLong,
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsernameError::TooShort => {
+                write!(f, "Username must be at least {MIN_USERNAME_LEN} characters")
+            }
+            UsernameError::TooLong => {
+                write!(f, "Username must be at most {MAX_USERNAME_LEN} characters")
+            }
+            UsernameError::InvalidCharacter(c) => write!(
+                f,
+                "Username may only contain letters, digits, '_' and '-', found {c:?}"
+            ),
+        }
+    }
+}
+
+// The `validator` form of `validate_username`, for request types to annotate a field with.
+pub fn username_rule(username: &str) -> Result<(), ValidationError> {
+    validate_username(username)
+        .map_err(|err| ValidationError::new("username").with_message(err.to_string().into()))
+}
+
+// Expects the name to have been trimmed already. Only applies to new registrations; accounts
+// created before it existed keep their names.
+pub fn validate_username(username: &str) -> Result<(), UsernameError> {
+    if let Some(c) = username
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-'))
+    {
+        return Err(UsernameError::InvalidCharacter(c));
+    }
+    let len = username.chars().count();
+    if len < MIN_USERNAME_LEN {
+        return Err(UsernameError::TooShort);
+    }
+    if len > MAX_USERNAME_LEN {
+        return Err(UsernameError::TooLong);
+    }
+    Ok(())
+}
+
+// The public view of a user; anything returning a user to a client goes through this.
+#[derive(Serialize, Debug)]
+pub struct UserResponse {
+    pub id: u64,
+    pub username: String,
+    pub role: Role,
+}
+
+impl From<&User> for UserResponse {
+    fn from(user: &User) -> Self {
+        Self {
+            id: user.id,
+            username: user.username.clone(),
+            role: user.role,
+        }
+    }
+}
+
+// Unknown values fail query extraction, which `query_config` turns into a 400.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    Id,
+    Name,
+    CreatedAt,
+    Priority,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+// One owner's listing: which tasks, in what order, and which page of them. `None` filters
+// match everything.
+#[derive(Debug, Clone)]
+pub struct TaskQuery {
+    pub owner_id: u64,
+    pub include_deleted: bool,
+    pub completed: Option<bool>,
+    pub tag: Option<String>,
+    pub overdue: Option<bool>,
+    pub list_id: Option<u64>,
+    // What "overdue" is measured against.
+    pub now: DateTime<Utc>,
+    pub sort: SortKey,
+    pub order: SortOrder,
+    // Keyset cursor: only tasks after this id, in listing order. Only meaningful in id order.
+    pub after: Option<u64>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl TaskQuery {
+    // Every live task of the owner, in id order, unpaged.
+    pub fn new(owner_id: u64) -> Self {
+        Self {
+            owner_id,
+            include_deleted: false,
+            completed: None,
+            tag: None,
+            overdue: None,
+            list_id: None,
+            now: Utc::now(),
+            sort: SortKey::default(),
+            order: SortOrder::default(),
+            after: None,
+            offset: 0,
+            limit: usize::MAX,
+        }
+    }
+
+    fn matches(&self, task: &Task) -> bool {
+        task.owner_id == self.owner_id
+            && (self.include_deleted || task.deleted_at.is_none())
+            && self
+                .completed
+                .is_none_or(|completed| task.completed == completed)
+            && self
+                .tag
+                .as_deref()
+                .is_none_or(|tag| task.has_tag(tag.trim()))
+            && self
+                .overdue
+                .is_none_or(|overdue| task.is_overdue(self.now) == overdue)
+            && self.list_id.is_none_or(|list_id| task.list_id == list_id)
+            && self.after.is_none_or(|after| match self.order {
+                SortOrder::Asc => task.id > after,
+                SortOrder::Desc => task.id < after,
+            })
+    }
+}
+
+pub struct QueryPage<'a> {
+    pub tasks: Vec<&'a Task>,
+    // Matches before the cursor and paging were applied.
+    pub total: usize,
+    // Id of the last task on the page when more follow, to pass back as `after`.
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct CompactStats {
+    pub purged: usize,
+    pub dangling_dependencies: usize,
+}
+
+// One owner's live tasks split by completion, each group in id order.
+#[derive(Serialize, Debug)]
+pub struct TaskGroups<'a> {
+    pub active: Vec<&'a Task>,
+    pub completed: Vec<&'a Task>,
+}
+
+// Stable, so tasks that compare equal stay in id order.
+fn sort_tasks(tasks: &mut [&Task], key: SortKey, order: SortOrder) {
+    tasks.sort_by(|a, b| {
+        let ordering = match key {
+            SortKey::Id => a.id.cmp(&b.id),
+            SortKey::Name => a.name.cmp(&b.name),
+            SortKey::CreatedAt => a.created_at.cmp(&b.created_at),
+            SortKey::Priority => a.priority.cmp(&b.priority),
+        };
+        match order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    });
+}
+
+// How many earlier versions of each task are kept; the oldest is dropped past this.
+const MAX_TASK_HISTORY: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Database {
+    pub tasks: HashMap<u64, Task>,
+    pub users: HashMap<u64, User>,
+    #[serde(default = "first_task_id")]
+    pub next_id: u64,
+    // Versions each task had before its updates, newest first. Goes when the task does.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub history: HashMap<u64, VecDeque<Task>>,
+    #[serde(default)]
+    pub lists: HashMap<u64, TaskList>,
+    #[serde(default = "first_task_id")]
+    pub next_list_id: u64,
+    // Ids of deleted users. They are never handed out again, so tokens issued to a deleted
+    // account can't sign in as whoever registers the id next.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub retired_user_ids: BTreeSet<u64>,
+}
+
+fn first_task_id() -> u64 {
+    1
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Self {
+            tasks: HashMap::new(),
+            users: HashMap::new(),
+            next_id: first_task_id(),
+            history: HashMap::new(),
+            lists: HashMap::new(),
+            next_list_id: first_task_id(),
+            retired_user_ids: BTreeSet::new(),
+        }
+    }
+    // Files written by older versions, or edited by hand, may carry a stale counter. Moving it
+    // past the highest id already present keeps new tasks from reusing an existing id.
+    pub fn sync_next_id(&mut self) {
+        let after_highest = self.tasks.keys().max().map_or(first_task_id(), |id| id + 1);
+        self.next_id = self.next_id.max(after_highest);
+    }
+
+    // CRUD DATA
+    // Only reachable through `&mut Database`, i.e. under the state's write lock, so two
+    // requests can never be handed the same id.
+    fn next_task_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    // Stores a new task under the next free id, ignoring whatever id it came with, and
+    // without attachments. Returns None if that id is somehow already in use.
+    pub fn insert_new(&mut self, mut task: Task) -> Option<Task> {
+        task.id = self.next_task_id();
+        task.created_at = Utc::now();
+        task.updated_at = task.created_at;
+        task.deleted_at = None;
+        task.version = 1;
+        task.attachments.clear();
+        self.insert(task.clone()).then_some(task)
+    }
+
+    // Returns false, leaving the existing task alone, when the id is already taken.
+    pub fn insert(&mut self, task: Task) -> bool {
+        match self.tasks.entry(task.id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(task);
+                true
+            }
+        }
+    }
+
+    // Tasks in the trash are invisible here; see `get_including_deleted`.
+    pub fn get(&self, id: &u64) -> Option<&Task> {
+        self.tasks.get(id).filter(|task| task.deleted_at.is_none())
+    }
+
+    pub fn get_including_deleted(&self, id: &u64) -> Option<&Task> {
+        self.tasks.get(id)
+    }
+
+    // Sorted by id so that paging through the list is stable.
+    pub fn get_all(&self, owner_id: u64, include_deleted: bool) -> Vec<&Task> {
+        let mut tasks: Vec<&Task> = self
+            .tasks
+            .values()
+            .filter(|task| task.owner_id == owner_id)
+            .filter(|task| include_deleted || task.deleted_at.is_none())
+            .collect();
+        tasks.sort_by_key(|task| task.id);
+        tasks
+    }
+
+    // When any of the owner's tasks last changed, trashing included. Purging isn't tracked; it
+    // only removes tasks that were already in the trash.
+    pub fn last_modified(&self, owner_id: u64) -> Option<DateTime<Utc>> {
+        self.tasks
+            .values()
+            .filter(|task| task.owner_id == owner_id)
+            .map(|task| {
+                task.deleted_at
+                    .map_or(task.updated_at, |at| at.max(task.updated_at))
+            })
+            .max()
+    }
+
+    pub fn query(&self, query: &TaskQuery) -> QueryPage<'_> {
+        // The cursor narrows the page, not the total.
+        let unpaged = TaskQuery {
+            after: None,
+            ..query.clone()
+        };
+        let mut tasks: Vec<&Task> = self
+            .tasks
+            .values()
+            .filter(|task| unpaged.matches(task))
+            .collect();
+        let total = tasks.len();
+        tasks.retain(|task| query.matches(task));
+        let has_more = tasks.len() > query.offset.saturating_add(query.limit);
+        sort_tasks(&mut tasks, query.sort, query.order);
+        let tasks: Vec<&Task> = tasks
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit)
+            .collect();
+        let next_cursor = match (query.sort, tasks.last()) {
+            (SortKey::Id, Some(last)) if has_more => Some(last.id),
+            _ => None,
+        };
+        QueryPage {
+            tasks,
+            total,
+            next_cursor,
+        }
+    }
+
+    // Case-insensitive substring match on the name, limited to one owner's tasks.
+    pub fn search(&self, owner_id: u64, query: &str) -> Vec<&Task> {
+        let query = query.to_lowercase();
+        let mut tasks = self.get_all(owner_id, false);
+        tasks.retain(|task| task.name.to_lowercase().contains(&query));
+        tasks
+    }
+
+    // Typo-tolerant variant of `search`: each task is scored by how far the query is from the
+    // closest run of words in its name, as a share of the query length, and kept when that is
+    // at most `threshold`. Best matches come first.
+    pub fn fuzzy_search(&self, owner_id: u64, query: &str, threshold: f64) -> Vec<&Task> {
+        let query: Vec<char> = query.to_lowercase().chars().collect();
+        let query_words = query
+            .split(|c| c.is_whitespace())
+            .filter(|w| !w.is_empty())
+            .count();
+        let mut scored: Vec<(f64, &Task)> = self
+            .get_all(owner_id, false)
+            .into_iter()
+            .filter_map(|task| {
+                let name = task.name.to_lowercase();
+                let words: Vec<&str> = name.split_whitespace().collect();
+                let distance = words
+                    .windows(query_words.clamp(1, words.len().max(1)))
+                    .map(|window| {
+                        let candidate: Vec<char> = window.join(" ").chars().collect();
+                        levenshtein(&query, &candidate)
+                    })
+                    .min()?;
+                let score = distance as f64 / query.len().max(1) as f64;
+                (score <= threshold).then_some((score, task))
+            })
+            .collect();
+        scored.sort_by(|(a, a_task), (b, b_task)| a.total_cmp(b).then(a_task.id.cmp(&b_task.id)));
+        scored.into_iter().map(|(_, task)| task).collect()
+    }
+
+    pub fn task_count(&self) -> usize {
+        self.tasks
+            .values()
+            .filter(|task| task.deleted_at.is_none())
+            .count()
+    }
+
+    // Live tasks of one owner as (total, completed), without collecting them.
+    pub fn count_for(&self, owner_id: u64) -> (usize, usize) {
+        self.tasks
+            .values()
+            .filter(|task| task.owner_id == owner_id && task.deleted_at.is_none())
+            .fold((0, 0), |(total, completed), task| {
+                (total + 1, completed + usize::from(task.completed))
+            })
+    }
+
+    pub fn grouped(&self, owner_id: u64) -> TaskGroups<'_> {
+        let (completed, active) = self
+            .get_all(owner_id, false)
+            .into_iter()
+            .partition(|task| task.completed);
+        TaskGroups { active, completed }
+    }
+
+    // Moves a task to the trash. Returns whether a live task was actually deleted.
+    pub fn delete(&mut self, id: &u64) -> bool {
+        match self.tasks.get_mut(id) {
+            Some(task) if task.deleted_at.is_none() => {
+                task.deleted_at = Some(Utc::now());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Takes a task back out of the trash. Returns false if there was nothing to restore.
+    pub fn restore(&mut self, id: &u64) -> bool {
+        match self.tasks.get_mut(id) {
+            Some(task) if task.deleted_at.is_some() => {
+                task.deleted_at = None;
+                task.touch();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Purges the trash past `cutoff`, drops dependencies on tasks that no longer exist and
+    // resyncs the id counter.
+    pub fn compact(&mut self, cutoff: DateTime<Utc>) -> CompactStats {
+        let purged = self.purge_deleted(cutoff);
+        let ids: HashSet<u64> = self.tasks.keys().copied().collect();
+        let mut dangling_dependencies = 0;
+        for task in self.tasks.values_mut() {
+            let before = task.depends_on.len();
+            task.depends_on.retain(|id| ids.contains(id));
+            dangling_dependencies += before - task.depends_on.len();
+        }
+        self.sync_next_id();
+        CompactStats {
+            purged,
+            dangling_dependencies,
+        }
+    }
+
+    // Permanently removes tasks that have been in the trash since before `cutoff`.
+    pub fn purge_deleted(&mut self, cutoff: DateTime<Utc>) -> usize {
+        let before = self.tasks.len();
+        self.tasks.retain(|_, task| {
+            task.deleted_at
+                .is_none_or(|deleted_at| deleted_at >= cutoff)
+        });
+        self.forget_history_of_removed();
+        before - self.tasks.len()
+    }
+
+    // Replaces a live task and returns the previous version; never creates one.
+    pub fn update(&mut self, task: Task) -> Option<Task> {
+        let existing = self
+            .tasks
+            .get_mut(&task.id)
+            .filter(|existing| existing.deleted_at.is_none())?;
+        let previous = std::mem::replace(existing, task);
+        let history = self.history.entry(previous.id).or_default();
+        history.push_front(previous.clone());
+        history.truncate(MAX_TASK_HISTORY);
+        Some(previous)
+    }
+
+    pub fn history(&self, id: &u64) -> impl Iterator<Item = &Task> {
+        self.history.get(id).into_iter().flatten()
+    }
+
+    fn forget_history_of_removed(&mut self) {
+        let tasks = &self.tasks;
+        self.history.retain(|id, _| tasks.contains_key(id));
+    }
+
+    // Checks `task` against the stored tasks before it is written; `previous` is the stored
+    // version it replaces, if any. Only newly added dependencies have to exist, so a task
+    // whose prerequisite was deleted since can still be edited, and a deleted prerequisite no
+    // longer blocks completion.
+    pub fn check_dependencies(
+        &self,
+        task: &Task,
+        previous: Option<&Task>,
+    ) -> Result<(), DependencyError> {
+        let existing = previous.map_or(&[][..], |previous| &previous.depends_on[..]);
+        for id in task.depends_on.iter().filter(|id| !existing.contains(id)) {
+            if self.get(id).is_none_or(|dep| dep.owner_id != task.owner_id) {
+                return Err(DependencyError::Unknown(*id));
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let mut pending = task.depends_on.clone();
+        while let Some(id) = pending.pop() {
+            if id == task.id {
+                return Err(DependencyError::Cycle);
+            }
+            if seen.insert(id) {
+                if let Some(dep) = self.tasks.get(&id) {
+                    pending.extend(&dep.depends_on);
+                }
+            }
+        }
+
+        let was_completed = previous.is_some_and(|previous| previous.completed);
+        if task.completed && !was_completed {
+            if let Some(id) = task
+                .depends_on
+                .iter()
+                .find(|id| self.get(id).is_some_and(|dep| !dep.completed))
+            {
+                return Err(DependencyError::Unfinished(*id));
+            }
+        }
+        Ok(())
+    }
+
+    // Creates the next occurrence of every completed recurring task and returns the new tasks.
+    // The next due date is one period after the previous one, skipping ahead past `now` so a
+    // habit left alone for a while doesn't come back already overdue; tasks without a due
+    // date count from when they were completed.
+    //
+    // Owners with `max_per_owner` live tasks get nothing new. Their finished tasks keep the
+    // recurrence, to try again once there's room, and are returned second.
+    pub fn spawn_recurrences(
+        &mut self,
+        now: DateTime<Utc>,
+        max_per_owner: usize,
+    ) -> (Vec<Task>, Vec<u64>) {
+        let finished: Vec<u64> = self
+            .tasks
+            .values()
+            .filter(|task| task.completed && task.deleted_at.is_none() && task.recurrence.is_some())
+            .map(|task| task.id)
+            .collect();
+        let mut spawned = Vec::with_capacity(finished.len());
+        let mut held_back = Vec::new();
+        for id in finished {
+            let Some(owner_id) = self.tasks.get(&id).map(|task| task.owner_id) else {
+                continue;
+            };
+            if self.count_for(owner_id).0 >= max_per_owner {
+                held_back.push(id);
+                continue;
+            }
+            let Some(task) = self.tasks.get_mut(&id) else {
+                continue;
+            };
+            let Some(recurrence) = task.recurrence.take() else {
+                continue;
+            };
+            task.touch();
+            let mut next = task.clone();
+            let mut due_at = task.due_at.unwrap_or(task.updated_at) + recurrence.period();
+            while due_at <= now {
+                due_at += recurrence.period();
+            }
+            next.completed = false;
+            next.due_at = Some(due_at);
+            next.recurrence = Some(recurrence);
+            if let Some(next) = self.insert_new(next) {
+                spawned.push(next);
+            }
+        }
+        held_back.sort_unstable();
+        (spawned, held_back)
+    }
+
+    // Moves every completed task belonging to `owner_id` to the trash and returns their ids.
+    pub fn clear_completed(&mut self, owner_id: u64) -> Vec<u64> {
+        let now = Utc::now();
+        let mut deleted = Vec::new();
+        for task in self.tasks.values_mut() {
+            if task.owner_id == owner_id && task.completed && task.deleted_at.is_none() {
+                task.deleted_at = Some(now);
+                deleted.push(task.id);
+            }
+        }
+        deleted
+    }
+
+    // USER DATA RELATED FUNCTIONS
+    pub fn insert_user(&mut self, user: User) {
+        self.users.insert(user.id, user);
+    }
+
+    pub fn get_user(&self, id: &u64) -> Option<&User> {
+        self.users.get(id)
+    }
+
+    pub fn get_users(&self) -> Vec<&User> {
+        let mut users: Vec<&User> = self.users.values().collect();
+        users.sort_by_key(|user| user.id);
+        users
+    }
+
+    pub fn get_user_mut(&mut self, id: &u64) -> Option<&mut User> {
+        self.users.get_mut(id)
+    }
+
+    // Removes the user together with every task they own, trashed ones included, and returns
+    // the ids of the tasks that were removed.
+    pub fn remove_user(&mut self, id: &u64) -> Option<Vec<u64>> {
+        self.users.remove(id)?;
+        self.retired_user_ids.insert(*id);
+        let mut removed = Vec::new();
+        self.tasks.retain(|task_id, task| {
+            let owned = task.owner_id == *id;
+            if owned {
+                removed.push(*task_id);
+            }
+            !owned
+        });
+        self.forget_history_of_removed();
+        self.lists.retain(|_, list| list.owner_id != *id);
+        removed.sort_unstable();
+        Some(removed)
+    }
+
+    // The owner's own lists in id order, without the inbox.
+    pub fn lists_for(&self, owner_id: u64) -> Vec<&TaskList> {
+        let mut lists: Vec<&TaskList> = self
+            .lists
+            .values()
+            .filter(|list| list.owner_id == owner_id)
+            .collect();
+        lists.sort_unstable_by_key(|list| list.id);
+        lists
+    }
+
+    pub fn get_list(&self, owner_id: u64, id: &u64) -> Option<&TaskList> {
+        self.lists.get(id).filter(|list| list.owner_id == owner_id)
+    }
+
+    // Names are unique per owner, ignoring case, and "Inbox" is taken by the inbox.
+    pub fn create_list(&mut self, owner_id: u64, name: &str) -> Result<TaskList, ListError> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_LIST_NAME_LEN {
+            return Err(ListError::InvalidName);
+        }
+        let taken = name.to_lowercase() == INBOX_NAME.to_lowercase()
+            || self
+                .lists_for(owner_id)
+                .iter()
+                .any(|list| list.name.to_lowercase() == name.to_lowercase());
+        if taken {
+            return Err(ListError::NameTaken);
+        }
+        let list = TaskList {
+            id: self.next_list_id,
+            owner_id,
+            name: name.to_string(),
+        };
+        self.next_list_id += 1;
+        self.lists.insert(list.id, list.clone());
+        Ok(list)
+    }
+
+    // The task has to go into the inbox or one of its owner's lists.
+    pub fn check_list(&self, task: &Task) -> Result<(), ListError> {
+        if is_inbox(&task.list_id) || self.get_list(task.owner_id, &task.list_id).is_some() {
+            Ok(())
+        } else {
+            Err(ListError::Unknown(task.list_id))
+        }
+    }
+
+    // Live tasks of the owner in one list, the inbox included.
+    pub fn list_task_count(&self, owner_id: u64, id: &u64) -> usize {
+        self.tasks
+            .values()
+            .filter(|task| {
+                task.owner_id == owner_id && task.list_id == *id && task.deleted_at.is_none()
+            })
+            .count()
+    }
+
+    // Deletes a list, refusing while it still has live tasks unless `cascade` is set, in
+    // which case they go to the trash. Tasks left behind move to the inbox, so a restored
+    // task never points at a list that is gone. Returns the ids of the trashed tasks.
+    pub fn delete_list(
+        &mut self,
+        owner_id: u64,
+        id: &u64,
+        cascade: bool,
+    ) -> Result<Vec<u64>, ListError> {
+        if self.get_list(owner_id, id).is_none() {
+            return Err(ListError::Unknown(*id));
+        }
+        let live = self.list_task_count(owner_id, id);
+        if live > 0 && !cascade {
+            return Err(ListError::NotEmpty(live));
+        }
+        self.lists.remove(id);
+        let now = Utc::now();
+        let mut trashed = Vec::new();
+        for task in self.tasks.values_mut().filter(|task| task.list_id == *id) {
+            if task.deleted_at.is_none() {
+                task.deleted_at = Some(now);
+                trashed.push(task.id);
+            }
+            task.list_id = INBOX_LIST_ID;
+            task.touch();
+        }
+        trashed.sort_unstable();
+        Ok(trashed)
+    }
+
+    // Case-insensitive, so `Alice` and `alice` are one account; the name is kept as it was
+    // registered for display. Usernames are ASCII-only, see `validate_username`.
+    pub fn get_user_by_name(&self, username: &str) -> Option<&User> {
+        self.users
+            .values()
+            .find(|user| user.username.eq_ignore_ascii_case(username))
+    }
+}
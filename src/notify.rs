@@ -0,0 +1,97 @@
+use actix_web::web;
+use async_trait::async_trait;
+use reqwest::{Client as HttpClient, StatusCode};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use std::fmt;
+
+use crate::events::TaskEvent;
+use crate::state::AppState;
+use crate::webhook::deliver;
+
+// NOTIFICATIONS
+// Every task event is also handed to the configured `Notifier`, one at a time and in order,
+// by a background task; a slow or failing notifier never holds up a request. Without
+// TR_NOTIFY_URL nothing is sent.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn notify(&self, event: &TaskEvent) -> Result<(), NotifyError>;
+}
+
+#[derive(Debug)]
+pub enum NotifyError {
+    Request(reqwest::Error),
+    Refused(StatusCode),
+}
+
+impl fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotifyError::Request(err) => write!(f, "request failed: {err}"),
+            NotifyError::Refused(status) => write!(f, "refused with {status}"),
+        }
+    }
+}
+
+pub struct NoopNotifier;
+
+#[async_trait]
+impl Notifier for NoopNotifier {
+    async fn notify(&self, _event: &TaskEvent) -> Result<(), NotifyError> {
+        Ok(())
+    }
+}
+
+// POSTs `{"type": ..., "task_id": ..., "owner_id": ...}` to one URL for every event.
+pub struct WebhookNotifier {
+    client: HttpClient,
+    url: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(client: HttpClient, url: String) -> Self {
+        Self { client, url }
+    }
+}
+
+// Subscribers only see their own events, so `TaskEvent` leaves the owner out; a service
+// hearing about everyone's needs it.
+#[derive(Serialize)]
+struct Notification<'a> {
+    #[serde(flatten)]
+    event: &'a TaskEvent,
+    owner_id: u64,
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn notify(&self, event: &TaskEvent) -> Result<(), NotifyError> {
+        let notification = Notification {
+            event,
+            owner_id: event.owner_id,
+        };
+        deliver(&self.client, &self.url, &notification).await
+    }
+}
+
+// Takes a receiver rather than subscribing itself, so no event published between spawning
+// it and its first poll is missed.
+pub async fn run_notifier(
+    app_state: web::Data<AppState>,
+    mut events: broadcast::Receiver<TaskEvent>,
+) {
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                if let Err(err) = app_state.notifier.notify(&event).await {
+                    tracing::error!(%err, task_id = event.task_id, "failed to send notification");
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!(missed, "notifier fell behind, skipping events");
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::{web, Error};
+use futures_util::future::{ready, Either};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::error::ApiError;
+use crate::state::AppState;
+
+// Per-IP token bucket guarding the credential endpoints against online guessing.
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    pub buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, per_minute: u32) -> Self {
+        Self {
+            capacity: f64::from(capacity),
+            refill_per_sec: f64::from(per_minute) / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes a token for `ip`, or returns how long until the next one becomes available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if self.refill_per_sec > 0.0 {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.refill_per_sec,
+            ))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+
+    // Buckets that have refilled completely carry no state worth keeping.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * self.refill_per_sec < self.capacity
+            });
+    }
+}
+
+// How soon a client turned away by `limit_writes` is told to come back.
+const OVERLOADED_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+// Middleware capping how many writes are in flight at once. Past the limit a write is
+// answered 503 straight away instead of queueing for the database lock without bound. Reads
+// are never held back, so neither are the event streams.
+pub fn limit_writes<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let permit = match *req.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => None,
+        _ => req
+            .app_data::<web::Data<AppState>>()
+            .map(|app_state| app_state.write_permits.clone().try_acquire_owned()),
+    };
+    match permit {
+        Some(Err(_)) => Either::Right(ready(Err(
+            ApiError::overloaded(OVERLOADED_RETRY_AFTER).into()
+        ))),
+        permit => {
+            let response = srv.call(req);
+            Either::Left(async move {
+                let _permit = permit;
+                response.await
+            })
+        }
+    }
+}
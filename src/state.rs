@@ -0,0 +1,378 @@
+use actix_web::http::StatusCode;
+use actix_web::web;
+use chrono::Utc;
+use reqwest::Client as HttpClient;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use uuid::Uuid;
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+use crate::attachments::{
+    prune_attachments, DEFAULT_ATTACHMENTS_DIR, DEFAULT_MAX_ATTACHMENT_BYTES,
+};
+use crate::audit::AuditEvent;
+use crate::auth::{unix_now, Keyring, DEFAULT_MIN_PASSWORD_LEN, DEFAULT_REFRESH_TOKEN_DAYS};
+use crate::config::{
+    DEFAULT_IMPORT_LIMIT_BYTES, DEFAULT_LIST_MAX_AGE_SECS, DEFAULT_MAX_CONCURRENT_WRITES,
+    DEFAULT_MAX_TASKS_PER_USER, DEFAULT_TRASH_RETENTION_DAYS,
+};
+use crate::error::ApiError;
+use crate::events::{TaskEvent, TaskEventKind, EVENT_CHANNEL_CAPACITY};
+use crate::metrics::Metrics;
+use crate::models::{Database, Task};
+use crate::notify::{NoopNotifier, Notifier};
+use crate::rate_limit::RateLimiter;
+use crate::storage::Storage;
+use crate::webhook::{spawn_webhook, TASK_COMPLETED};
+
+// How long a create request can be retried with the same `Idempotency-Key`.
+const IDEMPOTENCY_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+struct IdempotentResult {
+    task: Task,
+    expires_at: u64,
+}
+
+struct RefreshToken {
+    user_id: u64,
+    expires_at: u64,
+}
+
+pub struct AppState {
+    pub db: RwLock<Database>,
+    pub storage: Box<dyn Storage>,
+    pub dirty: mpsc::Sender<()>,
+    pub metrics: Metrics,
+    // Token ids revoked by logout, mapped to their expiry so they can be pruned once the
+    // token would have been rejected anyway.
+    revoked_tokens: Mutex<HashMap<String, u64>>,
+    // Opaque refresh tokens handed out at login. They only live in memory, so a restart
+    // signs everyone out once their access token runs out.
+    refresh_tokens: Mutex<HashMap<String, RefreshToken>>,
+    // Tasks created under an `Idempotency-Key`, by user and key. Kept in memory only.
+    idempotency_keys: Mutex<HashMap<(u64, String), IdempotentResult>>,
+    pub auth_rate_limiter: RateLimiter,
+    // Signs and verifies access tokens.
+    pub keyring: Keyring,
+    // How long a refresh token is valid for; TR_REFRESH_EXP_DAYS.
+    pub refresh_token_lifetime: Duration,
+    // Shortest password accepted on registration and password change.
+    pub min_password_len: usize,
+    // Registering with one of these names makes an admin; see `config::admin_usernames`.
+    pub admin_usernames: Vec<String>,
+    pub events: broadcast::Sender<TaskEvent>,
+    pub http_client: HttpClient,
+    // Hosts users' webhooks may reach even though they resolve to internal addresses.
+    pub webhook_allowed_hosts: Vec<String>,
+    // Told about every task event by `run_notifier`.
+    pub notifier: Box<dyn Notifier>,
+    // How many live tasks one user may have; tasks in the trash don't count.
+    pub max_tasks_per_user: usize,
+    // How long deleted tasks stay restorable before they are purged.
+    pub trash_retention: chrono::Duration,
+    // One permit per write in flight; see `limit_writes`.
+    pub write_permits: Arc<Semaphore>,
+    // Saves one at a time, so a save is never overwritten by one with an older snapshot.
+    save_lock: tokio::sync::Mutex<()>,
+    // Where attachment files are kept, and how large one may be.
+    pub attachments_dir: PathBuf,
+    pub max_attachment_bytes: usize,
+    // Largest body `/tasks/import` reads before answering 413.
+    pub import_limit_bytes: usize,
+    // `max-age` of the private `Cache-Control` sent with task listings.
+    pub list_max_age_secs: u32,
+    // None keeps no audit trail, e.g. when nothing is persisted.
+    pub audit_log: Option<mpsc::UnboundedSender<AuditEvent>>,
+    // While set, every write is refused and the background jobs leave the data alone, so the
+    // database file can be copied safely.
+    read_only: AtomicBool,
+}
+
+impl AppState {
+    pub fn new(
+        db: Database,
+        storage: Box<dyn Storage>,
+        dirty: mpsc::Sender<()>,
+        auth_rate_limiter: RateLimiter,
+        http_client: HttpClient,
+        keyring: Keyring,
+    ) -> Self {
+        Self {
+            db: RwLock::new(db),
+            storage,
+            dirty,
+            metrics: Metrics::default(),
+            revoked_tokens: Mutex::new(HashMap::new()),
+            refresh_tokens: Mutex::new(HashMap::new()),
+            idempotency_keys: Mutex::new(HashMap::new()),
+            auth_rate_limiter,
+            keyring,
+            refresh_token_lifetime: Duration::from_secs(DEFAULT_REFRESH_TOKEN_DAYS * 24 * 60 * 60),
+            min_password_len: DEFAULT_MIN_PASSWORD_LEN,
+            admin_usernames: Vec::new(),
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            http_client,
+            webhook_allowed_hosts: Vec::new(),
+            notifier: Box::new(NoopNotifier),
+            max_tasks_per_user: DEFAULT_MAX_TASKS_PER_USER,
+            list_max_age_secs: DEFAULT_LIST_MAX_AGE_SECS,
+            attachments_dir: DEFAULT_ATTACHMENTS_DIR.into(),
+            max_attachment_bytes: DEFAULT_MAX_ATTACHMENT_BYTES,
+            import_limit_bytes: DEFAULT_IMPORT_LIMIT_BYTES,
+            trash_retention: chrono::Duration::days(DEFAULT_TRASH_RETENTION_DAYS.into()),
+            write_permits: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_WRITES)),
+            save_lock: tokio::sync::Mutex::new(()),
+            audit_log: None,
+            read_only: AtomicBool::new(false),
+        }
+    }
+
+    // A handler that panicked while holding the lock leaves it poisoned; the data is still
+    // usable, so recover the guard instead of failing every later request.
+    pub fn read_db(&self) -> RwLockReadGuard<'_, Database> {
+        self.db.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn write_db(&self) -> RwLockWriteGuard<'_, Database> {
+        self.db.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Writes the current data out now rather than waiting for the background save.
+    pub async fn save(&self) -> io::Result<()> {
+        let _saving = self.save_lock.lock().await;
+        let snapshot = self.read_db().clone();
+        self.storage.save(&snapshot).await
+    }
+
+    // Schedules a background save. The channel holds a single signal, so if it is full a
+    // save is already pending and will pick this change up as well.
+    pub fn mark_dirty(&self) {
+        let _ = self.dirty.try_send(());
+    }
+
+    // Fails once `owner_id` would go over `max_tasks_per_user` by adding `adding` tasks.
+    pub fn check_task_quota(
+        &self,
+        database: &Database,
+        owner_id: u64,
+        adding: usize,
+    ) -> Result<(), ApiError> {
+        let (live, _) = database.count_for(owner_id);
+        if live + adding > self.max_tasks_per_user {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "task_quota_exceeded",
+                format!(
+                    "A user can have at most {} tasks; delete some first",
+                    self.max_tasks_per_user
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    // Called first thing by every handler that changes stored data.
+    pub fn check_writable(&self) -> Result<(), ApiError> {
+        if self.is_read_only() {
+            return Err(ApiError::read_only());
+        }
+        Ok(())
+    }
+
+    // Only for successful changes; reads are never audited.
+    pub fn audit(&self, event: AuditEvent) {
+        if let Some(audit_log) = &self.audit_log {
+            let _ = audit_log.send(event);
+        }
+    }
+
+    // Calls the owner's webhook, if they set one, about a task that was just completed.
+    pub fn task_completed(&self, database: &Database, task: &Task) {
+        let url = database
+            .get_user(&task.owner_id)
+            .and_then(|owner| owner.webhook_url.clone());
+        if let Some(url) = url {
+            spawn_webhook(
+                self.http_client.clone(),
+                url,
+                self.webhook_allowed_hosts.clone(),
+                TASK_COMPLETED,
+                task.clone(),
+            );
+        }
+    }
+
+    // Sending only fails when nobody is subscribed, in which case there is no one to tell.
+    pub fn publish(&self, kind: TaskEventKind, owner_id: u64, task_id: u64) {
+        let _ = self.events.send(TaskEvent {
+            kind,
+            task_id,
+            owner_id,
+        });
+    }
+
+    pub fn revoke_token(&self, token_id: String, expires_at: u64) {
+        self.revoked_tokens
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(token_id, expires_at);
+    }
+
+    pub fn is_admin_username(&self, username: &str) -> bool {
+        self.admin_usernames
+            .iter()
+            .any(|admin| admin.eq_ignore_ascii_case(username))
+    }
+
+    pub fn is_revoked(&self, token_id: &str) -> bool {
+        self.revoked_tokens
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains_key(token_id)
+    }
+
+    fn prune_revoked_tokens(&self) {
+        let now = unix_now();
+        self.revoked_tokens
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, expires_at| *expires_at > now);
+        self.refresh_tokens
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, token| token.expires_at > now);
+        self.idempotency_keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, result| result.expires_at > now);
+    }
+
+    pub fn idempotent_result(&self, user_id: u64, key: &str) -> Option<Task> {
+        self.idempotency_keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&(user_id, key.to_string()))
+            .filter(|result| result.expires_at > unix_now())
+            .map(|result| result.task.clone())
+    }
+
+    pub fn remember_idempotent_result(&self, user_id: u64, key: String, task: &Task) {
+        self.idempotency_keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                (user_id, key),
+                IdempotentResult {
+                    task: task.clone(),
+                    expires_at: unix_now() + IDEMPOTENCY_WINDOW_SECS,
+                },
+            );
+    }
+
+    pub fn issue_refresh_token(&self, user_id: u64) -> String {
+        let token = Uuid::new_v4().simple().to_string();
+        let expires_at = unix_now() + self.refresh_token_lifetime.as_secs();
+        self.refresh_tokens
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                token.clone(),
+                RefreshToken {
+                    user_id,
+                    expires_at,
+                },
+            );
+        token
+    }
+
+    // Refresh tokens are single use: redeeming one removes it, whether or not it was still
+    // valid. Returns the user it was issued to.
+    pub fn redeem_refresh_token(&self, token: &str) -> Option<u64> {
+        self.refresh_tokens
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(token)
+            .filter(|token| token.expires_at > unix_now())
+            .map(|token| token.user_id)
+    }
+
+    pub fn revoke_refresh_tokens(&self, user_id: u64) {
+        self.refresh_tokens
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, token| token.user_id != user_id);
+    }
+}
+
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(60);
+
+// Drops expired token revocations, refresh tokens and idempotency keys, idle rate-limit
+// buckets and tasks that have been in the trash for longer than the retention period, and
+// schedules the next occurrence of completed recurring tasks.
+pub async fn run_housekeeping(app_state: web::Data<AppState>) {
+    let mut interval = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+    loop {
+        interval.tick().await;
+        app_state.prune_revoked_tokens();
+        app_state.auth_rate_limiter.prune();
+        if app_state.is_read_only() {
+            continue;
+        }
+
+        let purged = app_state
+            .write_db()
+            .purge_deleted(Utc::now() - app_state.trash_retention);
+        if purged > 0 {
+            tracing::info!(purged, "purged tasks from the trash");
+            app_state.mark_dirty();
+        }
+
+        let (spawned, held_back) = app_state
+            .write_db()
+            .spawn_recurrences(Utc::now(), app_state.max_tasks_per_user);
+        if !held_back.is_empty() {
+            tracing::warn!(tasks = ?held_back, "owners at the task quota; recurrences held back");
+        }
+        for task in &spawned {
+            app_state.publish(TaskEventKind::Created, task.owner_id, task.id);
+        }
+        if !spawned.is_empty() {
+            tracing::info!(spawned = spawned.len(), "scheduled recurring tasks");
+            app_state.mark_dirty();
+        }
+
+        let task_ids: HashSet<u64> = app_state.read_db().tasks.keys().copied().collect();
+        match prune_attachments(&app_state.attachments_dir, &task_ids).await {
+            Ok(0) => {}
+            Ok(pruned) => tracing::info!(pruned, "removed attachments of deleted tasks"),
+            Err(err) => tracing::error!(%err, "failed to prune attachments"),
+        }
+    }
+}
+
+// How long the writer waits after a change so that a burst of writes lands in one save.
+const SAVE_DEBOUNCE: Duration = Duration::from_millis(200);
+
+pub async fn persist_changes(app_state: web::Data<AppState>, mut dirty: mpsc::Receiver<()>) {
+    while dirty.recv().await.is_some() {
+        tokio::time::sleep(SAVE_DEBOUNCE).await;
+        while dirty.try_recv().is_ok() {}
+
+        if let Err(err) = app_state.save().await {
+            tracing::error!(%err, "failed to save database");
+        }
+    }
+}
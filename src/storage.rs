@@ -0,0 +1,371 @@
+use actix_web::web;
+use async_trait::async_trait;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::config::env_or;
+use crate::models::Database;
+
+// STORAGE BACKENDS
+// Handlers work against the in-memory `Database`; a `Storage` decides where snapshots of it
+// are persisted and where it is loaded from on startup.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // Returns None when nothing has been stored yet.
+    async fn load(&self) -> io::Result<Option<Database>>;
+    async fn save(&self, db: &Database) -> io::Result<()>;
+
+    // Bytes the stored data takes up, if the backend can tell.
+    async fn size(&self) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    // Gives back space earlier saves left unused; called right after a save.
+    async fn compact(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const DEFAULT_DB_PATH: &str = "database.json";
+const DEFAULT_SQLITE_URL: &str = "sqlite://database.sqlite";
+
+// The original single-file format, rewritten in full on every save, so there is nothing
+// left to compact.
+pub struct JsonStorage {
+    pub path: PathBuf,
+    // Indented output for inspecting and diffing the file by hand; compact otherwise.
+    pub pretty: bool,
+}
+
+#[async_trait]
+impl Storage for JsonStorage {
+    async fn load(&self) -> io::Result<Option<Database>> {
+        let path = self.path.clone();
+        match web::block(move || Database::load_from_file(&path))
+            .await
+            .map_err(io::Error::other)?
+        {
+            Ok(db) => Ok(Some(db)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn save(&self, db: &Database) -> io::Result<()> {
+        let snapshot = db.clone();
+        let path = self.path.clone();
+        let pretty = self.pretty;
+        web::block(move || snapshot.save_to_file(&path, pretty))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    async fn size(&self) -> io::Result<Option<u64>> {
+        match tokio::fs::metadata(&self.path).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// One row per task and per user, each holding the record as JSON, plus a key/value table
+// for database-wide values. Saves only write the rows that changed since the last save.
+pub struct SqliteStorage {
+    pool: SqlitePool,
+    written: tokio::sync::Mutex<HashMap<(&'static str, u64), String>>,
+}
+
+const SQLITE_TABLES: [&str; 4] = ["tasks", "users", "history", "lists"];
+
+impl SqliteStorage {
+    pub async fn connect(url: &str) -> io::Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)
+            .map_err(io::Error::other)?
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .map_err(io::Error::other)?;
+        for table in SQLITE_TABLES {
+            sqlx::query(&format!(
+                "CREATE TABLE IF NOT EXISTS {table} (id INTEGER PRIMARY KEY, data TEXT NOT NULL)"
+            ))
+            .execute(&pool)
+            .await
+            .map_err(io::Error::other)?;
+        }
+        sqlx::query("CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL)")
+            .execute(&pool)
+            .await
+            .map_err(io::Error::other)?;
+        Ok(Self {
+            pool,
+            written: tokio::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn rows(&self, table: &'static str) -> io::Result<Vec<(u64, String)>> {
+        let rows: Vec<(i64, String)> = sqlx::query_as(&format!("SELECT id, data FROM {table}"))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, data)| (id as u64, data))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn load(&self) -> io::Result<Option<Database>> {
+        let next_id: Option<(String,)> =
+            sqlx::query_as("SELECT value FROM meta WHERE key = 'next_id'")
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(io::Error::other)?;
+        let Some((next_id,)) = next_id else {
+            return Ok(None);
+        };
+
+        let mut db = Database::new();
+        db.next_id = next_id.parse().map_err(io::Error::other)?;
+        let mut written = self.written.lock().await;
+        for (id, data) in self.rows("tasks").await? {
+            db.tasks.insert(id, serde_json::from_str(&data)?);
+            written.insert(("tasks", id), data);
+        }
+        for (id, data) in self.rows("users").await? {
+            db.users.insert(id, serde_json::from_str(&data)?);
+            written.insert(("users", id), data);
+        }
+        for (id, data) in self.rows("history").await? {
+            db.history.insert(id, serde_json::from_str(&data)?);
+            written.insert(("history", id), data);
+        }
+        for (id, data) in self.rows("lists").await? {
+            db.lists.insert(id, serde_json::from_str(&data)?);
+            written.insert(("lists", id), data);
+        }
+        // Missing from stores written before lists existed.
+        let next_list_id: Option<(String,)> =
+            sqlx::query_as("SELECT value FROM meta WHERE key = 'next_list_id'")
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(io::Error::other)?;
+        if let Some((next_list_id,)) = next_list_id {
+            db.next_list_id = next_list_id.parse().map_err(io::Error::other)?;
+        }
+        let retired: Option<(String,)> =
+            sqlx::query_as("SELECT value FROM meta WHERE key = 'retired_user_ids'")
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(io::Error::other)?;
+        if let Some((retired,)) = retired {
+            db.retired_user_ids = serde_json::from_str(&retired)?;
+        }
+        Ok(Some(db))
+    }
+
+    async fn save(&self, db: &Database) -> io::Result<()> {
+        let mut rows = HashMap::new();
+        for task in db.tasks.values() {
+            rows.insert(("tasks", task.id), serde_json::to_string(task)?);
+        }
+        for user in db.users.values() {
+            rows.insert(("users", user.id), serde_json::to_string(user)?);
+        }
+        for (id, history) in &db.history {
+            rows.insert(("history", *id), serde_json::to_string(history)?);
+        }
+        for list in db.lists.values() {
+            rows.insert(("lists", list.id), serde_json::to_string(list)?);
+        }
+
+        let mut written = self.written.lock().await;
+        let mut tx = self.pool.begin().await.map_err(io::Error::other)?;
+        for ((table, id), data) in &rows {
+            if written.get(&(*table, *id)) != Some(data) {
+                sqlx::query(&format!(
+                    "INSERT INTO {table} (id, data) VALUES (?, ?) \
+                     ON CONFLICT(id) DO UPDATE SET data = excluded.data"
+                ))
+                .bind(*id as i64)
+                .bind(data)
+                .execute(&mut *tx)
+                .await
+                .map_err(io::Error::other)?;
+            }
+        }
+        for (table, id) in written.keys().filter(|key| !rows.contains_key(key)) {
+            sqlx::query(&format!("DELETE FROM {table} WHERE id = ?"))
+                .bind(*id as i64)
+                .execute(&mut *tx)
+                .await
+                .map_err(io::Error::other)?;
+        }
+        let meta = [
+            ("next_id", db.next_id.to_string()),
+            ("next_list_id", db.next_list_id.to_string()),
+            (
+                "retired_user_ids",
+                serde_json::to_string(&db.retired_user_ids)?,
+            ),
+        ];
+        for (key, value) in meta {
+            sqlx::query(
+                "INSERT INTO meta (key, value) VALUES (?, ?) \
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            )
+            .bind(key)
+            .bind(value)
+            .execute(&mut *tx)
+            .await
+            .map_err(io::Error::other)?;
+        }
+        tx.commit().await.map_err(io::Error::other)?;
+
+        *written = rows;
+        Ok(())
+    }
+
+    async fn size(&self) -> io::Result<Option<u64>> {
+        let (bytes,): (i64,) = sqlx::query_as(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(io::Error::other)?;
+        Ok(Some(bytes as u64))
+    }
+
+    // Deleted rows only leave free pages behind; VACUUM rebuilds the file without them.
+    async fn compact(&self) -> io::Result<()> {
+        sqlx::query("VACUUM")
+            .execute(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+// Keeps nothing: every instance starts empty and its data is gone when it stops. Used for
+// tests and throwaway instances.
+pub struct MemoryStorage;
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn load(&self) -> io::Result<Option<Database>> {
+        Ok(None)
+    }
+
+    async fn save(&self, _db: &Database) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// TR_STORAGE picks the backend: `json` (the default) or `sqlite`. TR_PERSIST=false overrides
+// it and keeps everything in memory.
+pub async fn storage_from_env() -> io::Result<Box<dyn Storage>> {
+    if !env_or("TR_PERSIST", true)? {
+        return Ok(Box::new(MemoryStorage));
+    }
+    match env::var("TR_STORAGE").as_deref() {
+        Err(_) | Ok("json") => Ok(Box::new(JsonStorage {
+            path: PathBuf::from(env::var("TR_DB_PATH").unwrap_or_else(|_| DEFAULT_DB_PATH.into())),
+            pretty: env_or("TR_DB_PRETTY", false)?,
+        })),
+        Ok("sqlite") => {
+            let url = env::var("TR_SQLITE_URL").unwrap_or_else(|_| DEFAULT_SQLITE_URL.into());
+            Ok(Box::new(SqliteStorage::connect(&url).await?))
+        }
+        Ok(other) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown TR_STORAGE={other:?}, expected json or sqlite"),
+        )),
+    }
+}
+
+// Nothing stored yet starts an empty database. Stored data that can't be read, even from the
+// `.bak` copy, is fatal when `strict` (TR_STRICT_LOAD, the default): starting empty would
+// have the next save overwrite it. Otherwise the service starts empty anyway.
+pub async fn load_database(storage: &dyn Storage, strict: bool) -> io::Result<Database> {
+    match storage.load().await {
+        Ok(Some(db)) => Ok(db),
+        Ok(None) => Ok(Database::new()),
+        Err(err) if strict => {
+            tracing::error!(%err, "stored database is unreadable, refusing to start");
+            Err(io::Error::new(
+                err.kind(),
+                format!(
+                    "failed to load the database: {err}; set TR_STRICT_LOAD=false to start empty"
+                ),
+            ))
+        }
+        Err(err) => {
+            tracing::error!(
+                %err,
+                "stored database is unreadable, starting empty; the next save overwrites it"
+            );
+            Ok(Database::new())
+        }
+    }
+}
+
+impl Database {
+    // The data is written to a temporary file next to the target and renamed over it, which
+    // is atomic on the same filesystem, so a crash mid-write never leaves a truncated file
+    // behind. The previous version is kept as `<path>.bak`.
+    fn save_to_file(&self, path: &Path, pretty: bool) -> io::Result<()> {
+        let data: String = if pretty {
+            serde_json::to_string_pretty(&self)?
+        } else {
+            serde_json::to_string(&self)?
+        };
+        let tmp_path = with_suffix(path, ".tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(data.as_bytes())?;
+        file.sync_all()?;
+        if path.exists() {
+            fs::copy(path, with_suffix(path, ".bak"))?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+
+    // Falls back to the `.bak` copy when the main file exists but can't be parsed.
+    fn load_from_file(path: &Path) -> io::Result<Self> {
+        match Self::read_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                let backup = with_suffix(path, ".bak");
+                tracing::warn!(
+                    %err,
+                    path = %path.display(),
+                    backup = %backup.display(),
+                    "database file is unreadable, trying the backup"
+                );
+                Self::read_file(&backup).map_err(|_| err)
+            }
+            result => result,
+        }
+    }
+
+    fn read_file(path: &Path) -> io::Result<Self> {
+        let file_contents = fs::read_to_string(path)?;
+        let db: Database = serde_json::from_str(&file_contents)?;
+        Ok(db)
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
@@ -0,0 +1,2660 @@
+use actix_web::http::header::{self, ContentType};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse};
+use async_trait::async_trait;
+use jsonwebtoken::Algorithm;
+use reqwest::Client as HttpClient;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Semaphore};
+
+use std::collections::HashSet;
+use std::env;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::attachments::{attachment_path, prune_attachments};
+use crate::audit::AuditAction;
+use crate::auth::{check_password_strength, hash_password, Keyring, KeyringError, PasswordError};
+use crate::backup::write_backup;
+use crate::config::{
+    build_cors, env_nonzero, json_config, DEFAULT_AUTH_RATE_BURST, DEFAULT_AUTH_RATE_PER_MINUTE,
+    DEFAULT_JSON_LIMIT_BYTES,
+};
+use crate::error::{catch_panics, enforce_timeout};
+use crate::events::TaskEventKind;
+use crate::handlers::{api, route_not_found};
+use crate::metrics::track_requests;
+use crate::models::{
+    validate_username, Database, QueryPage, Recurrence, Role, SortKey, SortOrder, Task, TaskCsvRow,
+    TaskQuery, User, UsernameError,
+};
+use crate::notify::{run_notifier, WebhookNotifier};
+use crate::rate_limit::{limit_writes, RateLimiter};
+use crate::state::AppState;
+use crate::storage::{load_database, JsonStorage, MemoryStorage, SqliteStorage, Storage};
+
+fn test_keyring() -> Keyring {
+    Keyring::new(
+        Algorithm::HS256,
+        vec![("test".to_string(), "test-secret".to_string())],
+    )
+    .unwrap()
+}
+
+fn test_state() -> web::Data<AppState> {
+    state_with(Database::new(), |_| {})
+}
+
+// `configure` sets whatever the test needs that `main` would read from the environment.
+fn state_with(db: Database, configure: impl FnOnce(&mut AppState)) -> web::Data<AppState> {
+    // Nothing listens for save signals; `MemoryStorage` would drop them anyway.
+    let (dirty, _) = mpsc::channel(1);
+    let mut app_state = AppState::new(
+        db,
+        Box::new(MemoryStorage),
+        dirty,
+        RateLimiter::new(DEFAULT_AUTH_RATE_BURST, DEFAULT_AUTH_RATE_PER_MINUTE),
+        HttpClient::new(),
+        test_keyring(),
+    );
+    configure(&mut app_state);
+    web::Data::new(app_state)
+}
+
+macro_rules! init_app {
+    ($state:expr) => {
+        test::init_service(
+            App::new()
+                .app_data($state.clone())
+                .app_data(json_config(DEFAULT_JSON_LIMIT_BYTES))
+                .default_service(web::to(route_not_found))
+                .configure(|cfg| api(cfg, false)),
+        )
+        .await
+    };
+}
+
+// Inserts a user directly and returns a token for them, skipping the slow password hash.
+fn seed_user(state: &AppState, id: u64, username: &str) -> String {
+    state.write_db().insert_user(User {
+        id,
+        username: username.to_string(),
+        password_hash: String::new(),
+        role: Role::User,
+        webhook_url: None,
+    });
+    state.keyring.issue(id, Role::User)
+}
+
+fn bearer(token: &str) -> (header::HeaderName, String) {
+    (header::AUTHORIZATION, format!("Bearer {token}"))
+}
+
+#[actix_web::test]
+async fn register_then_login_returns_a_token() {
+    let state = test_state();
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/register")
+        .set_json(json!({ "id": 7, "username": "ada", "password": "correct horse" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/login")
+        .set_json(json!({ "username": "ada", "password": "correct horse" }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let claims = state
+        .keyring
+        .verify(body["token"].as_str().unwrap())
+        .unwrap();
+    assert_eq!(claims.sub, 7);
+}
+
+#[actix_web::test]
+async fn register_rejects_a_taken_username() {
+    let state = test_state();
+    seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/register")
+        .set_json(json!({ "id": 2, "username": "ada", "password": "correct horse" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "conflict");
+}
+
+#[actix_web::test]
+async fn login_rejects_an_unknown_user() {
+    let state = test_state();
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/login")
+        .set_json(json!({ "username": "nobody", "password": "whatever" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "unauthorized");
+}
+
+#[actix_web::test]
+async fn task_routes_require_a_token() {
+    let state = test_state();
+    let app = init_app!(state);
+
+    let req = test::TestRequest::get().uri("/api/v1/tasks").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn create_then_get_task() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "  write tests  ", "completed": false }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let location = resp.headers().get(header::LOCATION).unwrap().clone();
+    let created: Value = test::read_body_json(resp).await;
+    assert_eq!(location, format!("/api/v1/task/{}", created["id"]).as_str());
+    assert_eq!(created["name"], "write tests");
+    assert_eq!(created["owner_id"], 1);
+    assert_eq!(created["version"], 1);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/v1/task/{}", created["id"]))
+        .insert_header(bearer(&token))
+        .to_request();
+    let fetched: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(fetched, created);
+}
+
+#[actix_web::test]
+async fn create_rejects_an_empty_name() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "   ", "completed": false }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "invalid_task");
+}
+
+#[actix_web::test]
+async fn invalid_tasks_report_every_failing_field() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let tags: Vec<String> = (0..21).map(|i| format!("tag{i}")).collect();
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "x".repeat(257), "completed": false, "tags": tags }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(resp).await;
+    let fields = body["error"]["fields"].as_object().unwrap();
+    assert_eq!(
+        fields.keys().collect::<Vec<_>>(),
+        ["name", "tags"],
+        "{body}"
+    );
+    assert_eq!(fields["tags"][0], "A task can have at most 20 tags");
+}
+
+#[actix_web::test]
+async fn malformed_json_gets_an_api_error() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .insert_header(ContentType::json())
+        .set_payload("{bad json")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "invalid_json");
+    assert!(body["error"]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Invalid JSON body"));
+}
+
+#[actix_web::test]
+async fn list_only_returns_the_callers_tasks() {
+    let state = test_state();
+    let ada = seed_user(&state, 1, "ada");
+    let bob = seed_user(&state, 2, "bob");
+    let app = init_app!(state);
+
+    for (token, name) in [(&ada, "first"), (&bob, "theirs"), (&ada, "second")] {
+        let req = test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(token))
+            .set_json(json!({ "name": name, "completed": false }))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CREATED
+        );
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks")
+        .insert_header(bearer(&ada))
+        .to_request();
+    let page: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(page["total"], 2);
+    let names: Vec<&str> = page["tasks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|task| task["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["first", "second"]);
+}
+
+#[actix_web::test]
+async fn update_bumps_the_version_and_rejects_stale_writes() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "draft", "completed": false }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+
+    let req = test::TestRequest::put()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "id": created["id"], "name": "final", "completed": true, "version": 1 }))
+        .to_request();
+    let updated: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(updated["name"], "final");
+    assert_eq!(updated["version"], 2);
+    assert_eq!(updated["created_at"], created["created_at"]);
+
+    let req = test::TestRequest::put()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "id": created["id"], "name": "stale", "completed": false, "version": 1 }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "version_mismatch");
+}
+
+#[actix_web::test]
+async fn put_by_id_takes_the_path_id_and_rejects_a_different_body_id() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "draft", "completed": false }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let uri = format!("/api/v1/task/{}", created["id"]);
+
+    let req = test::TestRequest::put()
+        .uri(&uri)
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "final", "completed": false, "version": 1 }))
+        .to_request();
+    let updated: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(updated["id"], created["id"]);
+    assert_eq!(updated["name"], "final");
+
+    let req = test::TestRequest::put()
+        .uri(&uri)
+        .insert_header(bearer(&token))
+        .set_json(json!({ "id": 999, "name": "other", "completed": false, "version": 2 }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "invalid_task");
+    assert!(body["error"]["fields"]["id"].is_array());
+    assert_eq!(state.read_db().get(&1).unwrap().name, "final");
+}
+
+#[actix_web::test]
+async fn update_of_a_missing_task_is_not_found() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::put()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "id": 404, "name": "ghost", "completed": false }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn delete_hides_the_task_until_restored() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "temporary", "completed": false }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let uri = format!("/api/v1/task/{}", created["id"]);
+
+    let req = test::TestRequest::delete()
+        .uri(&uri)
+        .insert_header(bearer(&token))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NO_CONTENT
+    );
+
+    let req = test::TestRequest::get()
+        .uri(&uri)
+        .insert_header(bearer(&token))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let req = test::TestRequest::delete()
+        .uri(&uri)
+        .insert_header(bearer(&token))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let req = test::TestRequest::post()
+        .uri(&format!("{uri}/restore"))
+        .insert_header(bearer(&token))
+        .to_request();
+    let restored: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(restored["name"], "temporary");
+}
+
+#[actix_web::test]
+async fn other_users_tasks_are_hidden_and_protected() {
+    let state = test_state();
+    let ada = seed_user(&state, 1, "ada");
+    let bob = seed_user(&state, 2, "bob");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&ada))
+        .set_json(json!({ "name": "private", "completed": false }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let uri = format!("/api/v1/task/{}", created["id"]);
+
+    let req = test::TestRequest::get()
+        .uri(&uri)
+        .insert_header(bearer(&bob))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let req = test::TestRequest::delete()
+        .uri(&uri)
+        .insert_header(bearer(&bob))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_web::test]
+async fn me_never_exposes_the_password_hash() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/me")
+        .insert_header(bearer(&token))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["username"], "ada");
+    assert!(body.get("password_hash").is_none());
+}
+
+#[actix_web::test]
+async fn unknown_routes_get_an_api_error() {
+    let state = test_state();
+    let app = init_app!(state);
+
+    let req = test::TestRequest::get().uri("/nope").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "not_found");
+}
+
+#[actix_web::test]
+async fn unversioned_paths_still_work_but_are_deprecated() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::get()
+        .uri("/tasks")
+        .insert_header(bearer(&token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("deprecation").unwrap(), "true");
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks")
+        .insert_header(bearer(&token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.headers().get("deprecation").is_none());
+}
+
+#[actix_web::test]
+async fn complete_and_uncomplete_flip_the_flag() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "ship it", "completed": false }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let uri = format!("/api/v1/task/{}", created["id"]);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("{uri}/complete"))
+        .insert_header(bearer(&token))
+        .to_request();
+    let completed: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(completed["completed"], true);
+    assert_eq!(completed["version"], 2);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("{uri}/uncomplete"))
+        .insert_header(bearer(&token))
+        .to_request();
+    let reopened: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(reopened["completed"], false);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task/404/complete")
+        .insert_header(bearer(&token))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[actix_web::test]
+async fn ids_continue_after_the_highest_loaded_id() {
+    let task = |id: u64| {
+        json!({
+            "id": id,
+            "name": format!("task {id}"),
+            "completed": false,
+            "owner_id": 1,
+            "created_at": "2024-01-01T00:00:00Z",
+            "updated_at": "2024-01-01T00:00:00Z",
+        })
+    };
+    // Written before the counter was stored, so `next_id` falls back to its default.
+    let file = json!({ "tasks": { "5": task(5), "9": task(9) }, "users": {} });
+    let mut db: Database = serde_json::from_value(file).unwrap();
+    db.sync_next_id();
+    let state = state_with(db, |_| {});
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "fresh", "completed": false }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(created["id"], 10);
+}
+
+#[actix_web::test]
+async fn validate_username_enforces_length_and_charset() {
+    assert_eq!(validate_username("ada"), Ok(()));
+    assert_eq!(validate_username("grace_hopper-1906"), Ok(()));
+    assert_eq!(validate_username(&"a".repeat(32)), Ok(()));
+    assert_eq!(validate_username(""), Err(UsernameError::TooShort));
+    assert_eq!(validate_username("ab"), Err(UsernameError::TooShort));
+    assert_eq!(
+        validate_username(&"a".repeat(33)),
+        Err(UsernameError::TooLong)
+    );
+    assert_eq!(
+        validate_username("ada lovelace"),
+        Err(UsernameError::InvalidCharacter(' '))
+    );
+    assert_eq!(
+        validate_username("zoë"),
+        Err(UsernameError::InvalidCharacter('ë'))
+    );
+}
+
+#[actix_web::test]
+async fn register_trims_and_validates_the_username() {
+    let state = test_state();
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/register")
+        .set_json(json!({ "id": 1, "username": "a b", "password": "correct horse" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "invalid_username");
+    assert!(body["error"]["fields"]["username"][0]
+        .as_str()
+        .unwrap()
+        .contains("' '"));
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/register")
+        .set_json(json!({ "id": 1, "username": "  ada  ", "password": "correct horse" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    assert!(state.read_db().get_user_by_name("ada").is_some());
+}
+
+#[actix_web::test]
+async fn check_password_strength_rejects_short_and_common_passwords() {
+    assert_eq!(check_password_strength("correct horse", 8), Ok(()));
+    assert_eq!(check_password_strength("abcdefgh", 8), Ok(()));
+    assert_eq!(
+        check_password_strength("abcdefg", 8),
+        Err(PasswordError::TooShort(8))
+    );
+    assert_eq!(check_password_strength("abc", 3), Ok(()));
+    assert_eq!(
+        check_password_strength("PassWord1", 8),
+        Err(PasswordError::TooCommon)
+    );
+}
+
+#[actix_web::test]
+async fn register_rejects_a_weak_password() {
+    let state = test_state();
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/register")
+        .set_json(json!({ "id": 1, "username": "ada", "password": "short" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "weak_password");
+    assert!(state.read_db().get_user_by_name("ada").is_none());
+}
+
+#[actix_web::test]
+async fn task_changes_are_published_to_subscribers() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let mut events = state.events.subscribe();
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "watched", "completed": false }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/v1/task/{}", created["id"]))
+        .insert_header(bearer(&token))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let event = events.try_recv().unwrap();
+    assert_eq!(event.kind, TaskEventKind::Created);
+    assert_eq!(
+        (event.owner_id, json!(event.task_id)),
+        (1, created["id"].clone())
+    );
+    assert_eq!(events.try_recv().unwrap().kind, TaskEventKind::Deleted);
+}
+
+#[actix_web::test]
+async fn events_endpoint_opens_an_event_stream() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/events")
+        .insert_header(bearer(&token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/event-stream"
+    );
+    assert_eq!(state.events.receiver_count(), 1);
+    drop(resp);
+    assert_eq!(state.events.receiver_count(), 0);
+}
+
+#[actix_web::test]
+async fn count_reports_live_tasks_by_completion() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let other = seed_user(&state, 2, "grace");
+    let app = init_app!(state);
+
+    for (token, name, completed) in [
+        (&token, "open", false),
+        (&token, "done", true),
+        (&token, "trashed", true),
+        (&other, "not mine", false),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(token))
+            .set_json(json!({ "name": name, "completed": completed }))
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, req).await;
+        if name == "trashed" {
+            let req = test::TestRequest::delete()
+                .uri(&format!("/api/v1/task/{}", created["id"]))
+                .insert_header(bearer(token))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks/count")
+        .insert_header(bearer(&token))
+        .to_request();
+    let counts: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(counts, json!({ "total": 2, "completed": 1, "active": 1 }));
+}
+
+#[actix_web::test]
+async fn completing_a_recurring_task_schedules_the_next_one() {
+    let mut db = Database::new();
+    let task: Task = serde_json::from_value(json!({
+        "name": "water the plants",
+        "completed": true,
+        "owner_id": 1,
+        "due_at": "2024-01-01T09:00:00Z",
+        "recurrence": "weekly",
+    }))
+    .unwrap();
+    let id = db.insert_new(task).unwrap().id;
+    let now = "2024-01-10T00:00:00Z".parse().unwrap();
+
+    // At the cap, the next occurrence waits and the finished one keeps its recurrence.
+    let (spawned, held_back) = db.spawn_recurrences(now, 1);
+    assert_eq!((spawned.len(), held_back), (0, vec![id]));
+    assert_eq!(db.get(&id).unwrap().recurrence, Some(Recurrence::Weekly));
+
+    let (spawned, held_back) = db.spawn_recurrences(now, 2);
+    assert!(held_back.is_empty());
+    assert_eq!(spawned.len(), 1);
+    let next = serde_json::to_value(&spawned[0]).unwrap();
+    assert_eq!(next["completed"], false);
+    assert_eq!(next["due_at"], "2024-01-15T09:00:00Z");
+    assert_eq!(spawned[0].recurrence, Some(Recurrence::Weekly));
+    assert_eq!(db.get(&id).unwrap().recurrence, None);
+
+    // The finished occurrence gave up its recurrence, so nothing is scheduled twice.
+    let (spawned, held_back) = db.spawn_recurrences(now, 2);
+    assert!(spawned.is_empty() && held_back.is_empty());
+}
+
+#[actix_web::test]
+async fn dependencies_block_completion_and_reject_cycles() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let create = |body: Value| {
+        test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(&token))
+            .set_json(body)
+            .to_request()
+    };
+    let first: Value = test::call_and_read_body_json(
+        &app,
+        create(json!({ "name": "design", "completed": false })),
+    )
+    .await;
+    let second: Value = test::call_and_read_body_json(
+        &app,
+        create(json!({ "name": "build", "completed": false, "depends_on": [first["id"]] })),
+    )
+    .await;
+    let resp = test::call_service(
+        &app,
+        create(json!({ "name": "orphan", "completed": false, "depends_on": [404] })),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let complete = |id: &Value| {
+        test::TestRequest::post()
+            .uri(&format!("/api/v1/task/{id}/complete"))
+            .insert_header(bearer(&token))
+            .to_request()
+    };
+    let resp = test::call_service(&app, complete(&second["id"])).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "unfinished_dependency");
+
+    let resp = test::call_service(&app, complete(&first["id"])).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, complete(&second["id"])).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::patch()
+        .uri(&format!("/api/v1/task/{}", first["id"]))
+        .insert_header(bearer(&token))
+        .set_json(json!({ "depends_on": [second["id"]] }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "dependency_cycle");
+}
+
+#[actix_web::test]
+async fn refresh_tokens_rotate_on_use() {
+    let state = test_state();
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/register")
+        .set_json(json!({ "id": 1, "username": "ada", "password": "correct horse" }))
+        .to_request();
+    test::call_service(&app, req).await;
+    let req = test::TestRequest::post()
+        .uri("/api/v1/login")
+        .set_json(json!({ "username": "ada", "password": "correct horse" }))
+        .to_request();
+    let login: Value = test::call_and_read_body_json(&app, req).await;
+
+    let refresh = |token: &Value| {
+        test::TestRequest::post()
+            .uri("/api/v1/refresh")
+            .set_json(json!({ "refresh_token": token }))
+            .to_request()
+    };
+    let resp = test::call_service(&app, refresh(&login["refresh_token"])).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let refreshed: Value = test::read_body_json(resp).await;
+    assert_eq!(
+        state
+            .keyring
+            .verify(refreshed["token"].as_str().unwrap())
+            .unwrap()
+            .sub,
+        1
+    );
+    assert_ne!(refreshed["refresh_token"], login["refresh_token"]);
+
+    let resp = test::call_service(&app, refresh(&login["refresh_token"])).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/logout")
+        .insert_header(bearer(refreshed["token"].as_str().unwrap()))
+        .to_request();
+    test::call_service(&app, req).await;
+    let resp = test::call_service(&app, refresh(&refreshed["refresh_token"])).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn creating_past_the_task_quota_is_refused() {
+    let state = state_with(Database::new(), |state| state.max_tasks_per_user = 1);
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let create = || {
+        test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "name": "only one", "completed": false }))
+            .to_request()
+    };
+    let first: Value = test::call_and_read_body_json(&app, create()).await;
+    let resp = test::call_service(&app, create()).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "task_quota_exceeded");
+
+    // Restoring from the trash takes up room just like creating does.
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/v1/task/{}", first["id"]))
+        .insert_header(bearer(&token))
+        .to_request();
+    test::call_service(&app, req).await;
+    assert_eq!(
+        test::call_service(&app, create()).await.status(),
+        StatusCode::CREATED
+    );
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/v1/task/{}/restore", first["id"]))
+        .insert_header(bearer(&token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let first_id = first["id"].as_u64().unwrap();
+    assert!(state.read_db().get(&first_id).is_none());
+}
+
+#[actix_web::test]
+async fn mutations_are_audited_and_reads_are_not() {
+    let (audit_tx, mut audit_rx) = mpsc::unbounded_channel();
+    let state = state_with(Database::new(), |state| state.audit_log = Some(audit_tx));
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "audited", "completed": false }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let uri = format!("/api/v1/task/{}", created["id"]);
+    let req = test::TestRequest::get()
+        .uri(&uri)
+        .insert_header(bearer(&token))
+        .to_request();
+    test::call_service(&app, req).await;
+    let req = test::TestRequest::delete()
+        .uri(&uri)
+        .insert_header(bearer(&token))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let entries: Vec<_> = std::iter::from_fn(|| audit_rx.try_recv().ok()).collect();
+    let actions: Vec<_> = entries.iter().map(|entry| entry.action).collect();
+    assert_eq!(actions, [AuditAction::CreateTask, AuditAction::DeleteTask]);
+    assert!(entries
+        .iter()
+        .all(|entry| entry.actor == 1 && json!(entry.target) == created["id"]));
+}
+
+#[actix_web::test]
+async fn upsert_import_updates_known_ids_and_creates_the_rest() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "draft", "completed": false }))
+        .to_request();
+    let existing: Value = test::call_and_read_body_json(&app, req).await;
+    let id = &existing["id"];
+    let lines = [
+        json!({ "id": id, "name": "final", "completed": true, "version": 1 }),
+        json!({ "id": id, "name": "stale", "completed": false, "version": 1 }),
+        json!({ "id": 999, "name": "from another device", "completed": false }),
+    ]
+    .map(|line| line.to_string())
+    .join("\n");
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/tasks/import?upsert=true")
+        .insert_header(bearer(&token))
+        .set_payload(lines)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let body: Value = test::read_body_json(resp).await;
+    let outcomes: Vec<_> = body["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| {
+            result["action"]
+                .as_str()
+                .or(result["error"]["code"].as_str())
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(outcomes, ["updated", "version_mismatch", "created"]);
+    assert_eq!(body["results"][0]["id"], *id);
+    assert_eq!(
+        (&body["succeeded"], &body["failed"]),
+        (&json!(2), &json!(1))
+    );
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/v1/task/{id}"))
+        .insert_header(bearer(&token))
+        .to_request();
+    let stored: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        (&stored["name"], &stored["version"]),
+        (&json!("final"), &json!(2))
+    );
+}
+
+#[actix_web::test]
+async fn tasks_are_listed_as_csv_when_asked_for() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "milk, eggs and \"fresh\" bread", "completed": false, "tags": ["shop", "home"] }))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks")
+        .insert_header(bearer(&token))
+        .insert_header((header::ACCEPT, "text/csv, application/json;q=0.5"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/csv; charset=utf-8"
+    );
+    assert_eq!(resp.headers().get("x-total-count").unwrap(), "1");
+    assert_eq!(resp.headers().get(header::VARY).unwrap(), "Accept");
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+    let mut lines = body.lines();
+    // The header `csv` derives from the row, which the listing without rows has to match.
+    let csv_header = lines.next().unwrap();
+    assert_eq!(csv_header, TaskCsvRow::HEADERS.join(","));
+    assert!(csv_header.starts_with("id,name,completed,priority,tags,"));
+    assert!(lines
+        .next()
+        .unwrap()
+        .contains(r#","milk, eggs and ""fresh"" bread",false,medium,shop;home,"#));
+
+    // Nothing matches, but the columns are still named.
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks?completed=true")
+        .insert_header(bearer(&token))
+        .insert_header((header::ACCEPT, "text/csv"))
+        .to_request();
+    let empty = test::read_body(test::call_service(&app, req).await).await;
+    assert_eq!(
+        String::from_utf8(empty.to_vec()).unwrap(),
+        format!("{csv_header}\n")
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks")
+        .insert_header(bearer(&token))
+        .to_request();
+    let page: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(page["total"], 1);
+}
+
+#[actix_web::test]
+async fn retried_creates_with_an_idempotency_key_return_the_first_task() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let create = |key: &str| {
+        test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(&token))
+            .insert_header(("Idempotency-Key", key.to_string()))
+            .set_json(json!({ "name": "exactly once", "completed": false }))
+            .to_request()
+    };
+    let first: Value = test::call_and_read_body_json(&app, create("abc")).await;
+    let retry: Value = test::call_and_read_body_json(&app, create("abc")).await;
+    let other: Value = test::call_and_read_body_json(&app, create("def")).await;
+    assert_eq!(first, retry);
+    assert_ne!(first["id"], other["id"]);
+    assert_eq!(state.read_db().count_for(1), (2, 0));
+}
+
+#[actix_web::test]
+async fn read_only_mode_refuses_writes_but_serves_reads() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+    state.set_read_only(true);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "held back", "completed": false }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "read_only");
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks")
+        .insert_header(bearer(&token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    state.set_read_only(false);
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "let through", "completed": false }))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CREATED
+    );
+}
+
+#[actix_web::test]
+async fn fuzzy_search_tolerates_typos_and_ranks_by_distance() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    for name in ["buy groceries", "call mom", "sort out the grocery bags"] {
+        let req = test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "name": name, "completed": false }))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+    let search = |query: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/api/v1/tasks/search?q={query}"))
+            .insert_header(bearer(&token))
+            .to_request()
+    };
+    let names = |tasks: Value| -> Vec<String> {
+        tasks
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|task| task["name"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    let exact: Value = test::call_and_read_body_json(&app, search("grocerys")).await;
+    assert!(names(exact).is_empty());
+    let fuzzy: Value = test::call_and_read_body_json(&app, search("grocerys&fuzzy=true")).await;
+    assert_eq!(names(fuzzy), ["sort out the grocery bags", "buy groceries"]);
+    let strict: Value =
+        test::call_and_read_body_json(&app, search("grocerys&fuzzy=true&threshold=0.2")).await;
+    assert_eq!(names(strict), ["sort out the grocery bags"]);
+}
+
+#[actix_web::test]
+async fn backups_keep_only_the_newest_files() {
+    let dir = env::temp_dir().join(format!("tr-backups-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for stamp in ["20240101T000000Z", "20240102T000000Z"] {
+        std::fs::write(dir.join(format!("database-{stamp}.json")), "{}").unwrap();
+    }
+    std::fs::write(dir.join("notes.txt"), "not a backup").unwrap();
+
+    let newest = write_backup(&Database::new(), &dir, 2).unwrap();
+
+    let mut left: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    left.sort();
+    assert_eq!(
+        left,
+        [
+            dir.join("database-20240102T000000Z.json"),
+            newest,
+            dir.join("notes.txt")
+        ]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[actix_web::test]
+async fn bulk_delete_dry_run_reports_without_deleting() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "keep me", "completed": false }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let bulk_delete = |uri: &str| {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header(bearer(&token))
+            .set_json(json!({ "ids": [created["id"], 404] }))
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, bulk_delete("/api/v1/tasks/delete?dry_run=true")).await;
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let preview: Value = test::read_body_json(resp).await;
+    assert_eq!(preview["results"][1]["error"]["code"], "not_found");
+    assert_eq!(preview["removed"], json!([created["id"]]));
+    assert_eq!(preview["missing"], json!([404]));
+    assert_eq!(state.read_db().count_for(1), (1, 0));
+
+    let real: Value =
+        test::call_and_read_body_json(&app, bulk_delete("/api/v1/tasks/delete")).await;
+    assert_eq!(
+        (&real["removed"], &real["missing"]),
+        (&preview["removed"], &preview["missing"])
+    );
+    assert_eq!(state.read_db().count_for(1), (0, 0));
+}
+
+#[actix_web::test]
+async fn cursor_pages_are_not_shifted_by_deletes() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    for name in ["one", "two", "three", "four", "five"] {
+        let req = test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "name": name, "completed": false }))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+    let list = |uri: String| {
+        test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(bearer(&token))
+            .to_request()
+    };
+
+    let first: Value =
+        test::call_and_read_body_json(&app, list("/api/v1/tasks?limit=2".into())).await;
+    let ids: Vec<&Value> = first["tasks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|t| &t["id"])
+        .collect();
+    assert_eq!(ids, [&json!(1), &json!(2)]);
+    assert_eq!(first["next_cursor"], 2);
+
+    // Deleting a task already seen would make an offset of 2 skip task 3.
+    let req = test::TestRequest::delete()
+        .uri("/api/v1/task/1")
+        .insert_header(bearer(&token))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let second: Value =
+        test::call_and_read_body_json(&app, list("/api/v1/tasks?after=2&limit=2".into())).await;
+    let ids: Vec<&Value> = second["tasks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|t| &t["id"])
+        .collect();
+    assert_eq!(ids, [&json!(3), &json!(4)]);
+    let last: Value = test::call_and_read_body_json(
+        &app,
+        list(format!(
+            "/api/v1/tasks?after={}&limit=2",
+            second["next_cursor"]
+        )),
+    )
+    .await;
+    assert_eq!(last["tasks"][0]["id"], 5);
+    assert!(last.get("next_cursor").is_none());
+
+    let resp = test::call_service(&app, list("/api/v1/tasks?after=2&sort=name".into())).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn deleting_the_account_removes_its_tasks_and_tokens() {
+    let state = test_state();
+    let ada = seed_user(&state, 1, "ada");
+    let bob = seed_user(&state, 2, "bob");
+    state.write_db().get_user_mut(&1).unwrap().password_hash =
+        hash_password("correct horse").unwrap();
+    let app = init_app!(state);
+
+    for token in [&ada, &bob] {
+        let req = test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(token))
+            .set_json(json!({ "name": "errand", "completed": false }))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+    let delete_me = |password: &str| {
+        test::TestRequest::delete()
+            .uri("/api/v1/me")
+            .insert_header(bearer(&ada))
+            .set_json(json!({ "password": password }))
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, delete_me("wrong")).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(state.read_db().get_user(&1).is_some());
+
+    let resp = test::call_service(&app, delete_me("correct horse")).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert!(state.read_db().get_user(&1).is_none());
+    assert_eq!(state.read_db().count_for(1), (0, 0));
+    assert_eq!(state.read_db().count_for(2), (1, 0));
+
+    // Even a token that was never revoked no longer authenticates.
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks")
+        .insert_header(bearer(&state.keyring.issue(1, Role::User)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // The id stays retired, so those tokens can't come back to life for a new account.
+    let req = test::TestRequest::post()
+        .uri("/api/v1/register")
+        .set_json(json!({ "id": 1, "username": "eve", "password": "correct horse" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks")
+        .insert_header(bearer(&ada))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn usernames_are_case_insensitive() {
+    let state = test_state();
+    let app = init_app!(state);
+
+    let register = |username: &str, id: u64| {
+        test::TestRequest::post()
+            .uri("/api/v1/register")
+            .set_json(json!({ "id": id, "username": username, "password": "correct horse" }))
+            .to_request()
+    };
+    let resp = test::call_service(&app, register("Bob", 1)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, register("bob", 2)).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/login")
+        .set_json(json!({ "username": "bob", "password": "correct horse" }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let token = body["token"].as_str().unwrap();
+    assert_eq!(state.keyring.verify(token).unwrap().sub, 1);
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/me")
+        .insert_header(bearer(token))
+        .to_request();
+    let me: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(me["username"], "Bob");
+}
+
+#[actix_web::test]
+async fn query_applies_filters_sort_and_cursor_together() {
+    let mut db = Database::new();
+    for (name, completed, owner_id, tags) in [
+        ("b", false, 1, vec!["home"]),
+        ("a", true, 1, vec!["home"]),
+        ("c", false, 1, vec![]),
+        ("d", false, 1, vec!["home"]),
+        ("e", false, 2, vec!["home"]),
+    ] {
+        let task: Task = serde_json::from_value(json!({
+            "name": name,
+            "completed": completed,
+            "owner_id": owner_id,
+            "tags": tags,
+        }))
+        .unwrap();
+        db.insert_new(task).unwrap();
+    }
+    let names = |page: &QueryPage| {
+        page.tasks
+            .iter()
+            .map(|t| t.name.clone())
+            .collect::<Vec<_>>()
+    };
+
+    let open_home = TaskQuery {
+        completed: Some(false),
+        tag: Some("home".to_string()),
+        ..TaskQuery::new(1)
+    };
+    let page = db.query(&open_home);
+    assert_eq!(
+        (names(&page), page.total),
+        (vec!["b".into(), "d".into()], 2)
+    );
+
+    let by_name = TaskQuery {
+        sort: SortKey::Name,
+        order: SortOrder::Desc,
+        ..TaskQuery::new(1)
+    };
+    assert_eq!(names(&db.query(&by_name)), ["d", "c", "b", "a"]);
+
+    let first = db.query(&TaskQuery {
+        limit: 3,
+        ..TaskQuery::new(1)
+    });
+    assert_eq!(first.next_cursor, Some(3));
+    let rest = db.query(&TaskQuery {
+        after: first.next_cursor,
+        limit: 3,
+        ..TaskQuery::new(1)
+    });
+    assert_eq!(
+        (names(&rest), rest.total, rest.next_cursor),
+        (vec!["d".into()], 4, None)
+    );
+}
+
+#[actix_web::test]
+async fn task_list_is_privately_cacheable_and_conditional() {
+    // Last changed in an earlier second, so the listing carries a modification date.
+    let file = json!({
+        "tasks": {
+            "1": {
+                "id": 1,
+                "name": "poll me",
+                "completed": false,
+                "owner_id": 1,
+                "created_at": "2024-01-01T00:00:00Z",
+                "updated_at": "2024-01-01T00:00:00Z",
+            },
+        },
+        "users": {},
+        "next_id": 2,
+    });
+    let state = state_with(serde_json::from_value(file).unwrap(), |_| {});
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let create = |name: &str| {
+        test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "name": name, "completed": false }))
+            .to_request()
+    };
+    let list = |condition: Option<(header::HeaderName, &str)>| {
+        let mut req = test::TestRequest::get()
+            .uri("/api/v1/tasks")
+            .insert_header(bearer(&token));
+        if let Some((name, value)) = condition {
+            req = req.insert_header((name, value.to_string()));
+        }
+        req.to_request()
+    };
+    let header_of = |resp: &actix_web::dev::ServiceResponse, name: header::HeaderName| {
+        resp.headers()
+            .get(name)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+
+    let resp = test::call_service(&app, list(None)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CACHE_CONTROL).unwrap(),
+        "private, max-age=5"
+    );
+    let last_modified = header_of(&resp, header::LAST_MODIFIED);
+    assert_eq!(last_modified, "Mon, 01 Jan 2024 00:00:00 GMT");
+    let etag = header_of(&resp, header::ETAG);
+
+    let resp = test::call_service(&app, list(Some((header::IF_NONE_MATCH, &etag)))).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    // Echoing the server's own date back is how clients revalidate.
+    let resp = test::call_service(
+        &app,
+        list(Some((header::IF_MODIFIED_SINCE, &last_modified))),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    let resp = test::call_service(
+        &app,
+        list(Some((
+            header::IF_MODIFIED_SINCE,
+            "Sun, 31 Dec 2023 23:59:59 GMT",
+        ))),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // A change since then must not be hidden.
+    test::call_service(&app, create("and me")).await;
+    let resp = test::call_service(&app, list(Some((header::IF_NONE_MATCH, &etag)))).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(
+        &app,
+        list(Some((header::IF_MODIFIED_SINCE, &last_modified))),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn a_panicking_handler_gets_a_500_api_error() {
+    async fn boom() -> HttpResponse {
+        panic!("deliberate")
+    }
+    let app = test::init_service(
+        App::new()
+            .wrap_fn(catch_panics)
+            .route("/boom", web::get().to(boom)),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/boom").to_request();
+    let err = test::try_call_service(&app, req).await.unwrap_err();
+    // What the server writes back for an error that reaches the top.
+    let resp = err.error_response();
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["code"], "internal");
+}
+
+#[actix_web::test]
+async fn grouped_splits_the_callers_live_tasks_by_completion() {
+    let state = test_state();
+    let ada = seed_user(&state, 1, "ada");
+    let bob = seed_user(&state, 2, "bob");
+    let app = init_app!(state);
+
+    for (token, name, completed) in [
+        (&ada, "todo", false),
+        (&ada, "done", true),
+        (&ada, "binned", false),
+        (&bob, "not mine", false),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(token))
+            .set_json(json!({ "name": name, "completed": completed }))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+    state.write_db().delete(&3);
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks/grouped")
+        .insert_header(bearer(&ada))
+        .to_request();
+    let groups: Value = test::call_and_read_body_json(&app, req).await;
+    let names = |group: &str| {
+        groups[group]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|task| task["name"].clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names("active"), [json!("todo")]);
+    assert_eq!(names("completed"), [json!("done")]);
+}
+
+#[actix_web::test]
+async fn slow_handlers_time_out_with_a_504() {
+    async fn slow() -> HttpResponse {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        HttpResponse::Ok().finish()
+    }
+    let app = test::init_service(
+        App::new()
+            .wrap_fn(|req, srv| enforce_timeout(req, srv, Duration::from_secs(5)))
+            .route("/slow", web::get().to(slow)),
+    )
+    .await;
+    let slow_with = |timeout: &str| {
+        test::TestRequest::get()
+            .uri("/slow")
+            .insert_header(("x-request-timeout", timeout.to_string()))
+            .to_request()
+    };
+
+    let req = test::TestRequest::get().uri("/slow").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let err = test::try_call_service(&app, slow_with("0.05"))
+        .await
+        .unwrap_err();
+    assert_eq!(err.error_response().status(), StatusCode::GATEWAY_TIMEOUT);
+    let err = test::try_call_service(&app, slow_with("soon"))
+        .await
+        .unwrap_err();
+    assert_eq!(err.error_response().status(), StatusCode::BAD_REQUEST);
+}
+
+// Accepts one webhook call, reading until `until` shows up in the request, and answers 204.
+async fn receive_webhook(listener: &TcpListener, until: &str) -> String {
+    let receive = async {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        while !String::from_utf8_lossy(&request).contains(until) {
+            let read = socket.read(&mut buf).await.unwrap();
+            assert!(read > 0, "connection closed before the payload arrived");
+            request.extend_from_slice(&buf[..read]);
+        }
+        socket
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        String::from_utf8(request).unwrap()
+    };
+    tokio::time::timeout(Duration::from_secs(5), receive)
+        .await
+        .expect("the webhook was never called")
+}
+
+#[actix_web::test]
+async fn completing_a_task_calls_the_owners_webhook() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hook = format!("http://{}/hook", listener.local_addr().unwrap());
+    // The listener is on loopback, which only an operator can allow.
+    let state = state_with(Database::new(), |state| {
+        state.webhook_allowed_hosts = vec!["127.0.0.1".to_string()];
+    });
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::put()
+        .uri("/api/v1/me/webhook")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "url": "ftp://example.com" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let req = test::TestRequest::put()
+        .uri("/api/v1/me/webhook")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "url": hook }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "ship it", "completed": false }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/v1/task/{}/complete", created["id"]))
+        .insert_header(bearer(&token))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let received = receive_webhook(&listener, "ship it").await;
+    assert!(received.starts_with("POST /hook "));
+    assert!(received.contains(r#""event":"task.completed""#));
+}
+
+#[actix_web::test]
+async fn task_events_are_sent_to_the_notifier() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/notify", listener.local_addr().unwrap());
+    let state = state_with(Database::new(), |state| {
+        state.notifier = Box::new(WebhookNotifier::new(HttpClient::new(), url));
+    });
+    actix_web::rt::spawn(run_notifier(state.clone(), state.events.subscribe()));
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "announce me", "completed": false }))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let received = receive_webhook(&listener, "owner_id").await;
+    assert!(received.starts_with("POST /notify "));
+    assert!(received.contains(r#""type":"created""#));
+}
+
+#[actix_web::test]
+async fn compact_purges_expired_trash_and_dangling_dependencies() {
+    let task = |id: u64, deleted_at: Option<&str>, depends_on: Vec<u64>| {
+        json!({
+            "id": id,
+            "name": format!("task {id}"),
+            "completed": false,
+            "owner_id": 1,
+            "deleted_at": deleted_at,
+            "depends_on": depends_on,
+        })
+    };
+    let file = json!({
+        "tasks": {
+            "1": task(1, Some("2001-01-01T00:00:00Z"), vec![]),
+            "2": task(2, None, vec![1, 3]),
+            "3": task(3, None, vec![]),
+        },
+        "users": {},
+    });
+    let state = state_with(serde_json::from_value(file).unwrap(), |_| {});
+    seed_user(&state, 1, "ada");
+    let token = seed_user(&state, 2, "grace");
+    state.write_db().get_user_mut(&1).unwrap().role = Role::Admin;
+    let admin = state.keyring.issue(1, Role::Admin);
+    let app = init_app!(state);
+
+    let compact = |token: &str| {
+        test::TestRequest::post()
+            .uri("/api/v1/admin/compact")
+            .insert_header(bearer(token))
+            .to_request()
+    };
+    let resp = test::call_service(&app, compact(&token)).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let report: Value = test::call_and_read_body_json(&app, compact(&admin)).await;
+    assert_eq!(report["purged"], 1);
+    assert_eq!(report["dangling_dependencies"], 1);
+    assert_eq!(report["size_before"], Value::Null);
+    let db = state.read_db();
+    assert!(db.get_including_deleted(&1).is_none());
+    assert_eq!(db.get(&2).unwrap().depends_on, [3]);
+}
+
+#[cfg(feature = "demo")]
+#[actix_web::test]
+async fn seed_replaces_every_task_and_reset_keeps_only_the_admin() {
+    let state = test_state();
+    seed_user(&state, 1, "ada");
+    let token = seed_user(&state, 2, "grace");
+    state.write_db().get_user_mut(&1).unwrap().role = Role::Admin;
+    let admin = state.keyring.issue(1, Role::Admin);
+    let app = init_app!(state);
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "mine", "completed": false }))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CREATED
+    );
+
+    let post = |uri: &str, token: &str| {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header(bearer(token))
+            .to_request()
+    };
+    let resp = test::call_service(&app, post("/api/v1/admin/seed", &token)).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let seeded: Vec<Value> =
+        test::call_and_read_body_json(&app, post("/api/v1/admin/seed", &admin)).await;
+    assert!(!seeded.is_empty());
+    assert!(seeded.iter().all(|task| task["owner_id"] == 1));
+    assert!(state
+        .read_db()
+        .tasks
+        .values()
+        .all(|task| task.name != "mine"));
+
+    let report: Value =
+        test::call_and_read_body_json(&app, post("/api/v1/admin/reset", &admin)).await;
+    assert_eq!(report["tasks_removed"], seeded.len());
+    assert_eq!(report["users_removed"], 1);
+    let db = state.read_db();
+    assert!(db.tasks.is_empty());
+    assert_eq!(db.users.keys().collect::<Vec<_>>(), [&1]);
+    assert_eq!(db.next_id, 1);
+    assert!(db.retired_user_ids.contains(&2));
+}
+
+#[actix_web::test]
+async fn tokens_from_a_retired_key_verify_until_it_is_dropped() {
+    let key = |kid: &str, secret: &str| (kid.to_string(), secret.to_string());
+    let old = Keyring::new(Algorithm::HS256, vec![key("2023", "old-secret")]).unwrap();
+    let rotated = Keyring::new(
+        Algorithm::HS256,
+        vec![key("2024", "new-secret"), key("2023", "old-secret")],
+    )
+    .unwrap();
+    let dropped = Keyring::new(Algorithm::HS256, vec![key("2024", "new-secret")]).unwrap();
+
+    let old_token = old.issue(1, Role::User);
+    assert_eq!(rotated.verify(&old_token).unwrap().sub, 1);
+    assert!(dropped.verify(&old_token).is_none());
+    let new_token = rotated.issue(2, Role::User);
+    assert_eq!(dropped.verify(&new_token).unwrap().sub, 2);
+    assert!(old.verify(&new_token).is_none());
+
+    // A token claiming another algorithm is refused even with the right key.
+    let hs512 = Keyring::new(Algorithm::HS512, vec![key("2024", "new-secret")]).unwrap();
+    assert!(dropped.verify(&hs512.issue(3, Role::User)).is_none());
+
+    assert_eq!(
+        Keyring::new(Algorithm::RS256, vec![key("2024", "new-secret")]).err(),
+        Some(KeyringError::UnsupportedAlgorithm("RS256".to_string()))
+    );
+    assert_eq!(
+        Keyring::new(Algorithm::HS256, vec![key("a", "x"), key("a", "y")]).err(),
+        Some(KeyringError::DuplicateKeyId("a".to_string()))
+    );
+}
+
+#[actix_web::test]
+async fn writes_past_the_limit_are_turned_away() {
+    let state = state_with(Database::new(), |state| {
+        state.write_permits = Arc::new(Semaphore::new(1));
+    });
+    let token = seed_user(&state, 1, "ada");
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .wrap_fn(limit_writes)
+            .configure(|cfg| api(cfg, false)),
+    )
+    .await;
+    let create = || {
+        test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "name": "busy", "completed": false }))
+            .to_request()
+    };
+
+    // Another write is in flight.
+    let in_flight = state.write_permits.clone().try_acquire_owned().unwrap();
+    let err = test::try_call_service(&app, create()).await.unwrap_err();
+    let resp = err.error_response();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "1");
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks")
+        .insert_header(bearer(&token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    drop(in_flight);
+    let resp = test::call_service(&app, create()).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(state.write_permits.available_permits(), 1);
+}
+
+#[actix_web::test]
+async fn history_lists_earlier_versions_newest_first() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let other = seed_user(&state, 2, "grace");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "v1", "completed": false }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let id = created["id"].as_u64().unwrap();
+    for name in ["v2", "v3"] {
+        let req = test::TestRequest::patch()
+            .uri(&format!("/api/v1/task/{id}"))
+            .insert_header(bearer(&token))
+            .set_json(json!({ "name": name }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    let history = |token: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/api/v1/task/{id}/history"))
+            .insert_header(bearer(token))
+            .to_request()
+    };
+    let versions: Vec<Value> = test::call_and_read_body_json(&app, history(&token)).await;
+    let names: Vec<&str> = versions
+        .iter()
+        .map(|v| v["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["v2", "v1"]);
+    assert_eq!(versions[0]["version"], 2);
+    let resp = test::call_service(&app, history(&other)).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // Capped, and forgotten along with the task.
+    let mut db = state.write_db();
+    for n in 0..30 {
+        let mut task = db.get(&id).unwrap().clone();
+        task.name = format!("edit {n}");
+        db.update(task);
+    }
+    assert_eq!(db.history(&id).count(), 20);
+    assert_eq!(db.history(&id).next().unwrap().name, "edit 28");
+    db.delete(&id);
+    db.purge_deleted(chrono::Utc::now() + chrono::Duration::days(1));
+    assert!(db.history.is_empty());
+}
+
+#[actix_web::test]
+async fn tasks_can_be_filed_into_lists() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let other = seed_user(&state, 2, "grace");
+    let app = init_app!(state);
+
+    let new_list = |name: &str| {
+        test::TestRequest::post()
+            .uri("/api/v1/lists")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "name": name }))
+            .to_request()
+    };
+    let work: Value = test::call_and_read_body_json(&app, new_list(" Work ")).await;
+    assert_eq!(work["name"], "Work");
+    let list_id = work["id"].as_u64().unwrap();
+    for taken in ["work", "inbox"] {
+        let resp = test::call_service(&app, new_list(taken)).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+
+    let create = |body: Value| {
+        test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(&token))
+            .set_json(body)
+            .to_request()
+    };
+    let req = create(json!({ "name": "report", "completed": false, "list_id": list_id }));
+    let filed: Value = test::call_and_read_body_json(&app, req).await;
+    let req = create(json!({ "name": "loose end", "completed": false }));
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CREATED
+    );
+    let req = create(json!({ "name": "lost", "completed": false, "list_id": 99 }));
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "unknown_list");
+
+    let list_page = |list_id: u64| {
+        test::TestRequest::get()
+            .uri(&format!("/api/v1/tasks?list_id={list_id}"))
+            .insert_header(bearer(&token))
+            .to_request()
+    };
+    let page: Value = test::call_and_read_body_json(&app, list_page(list_id)).await;
+    assert_eq!(page["tasks"][0]["name"], "report");
+    assert_eq!(page["tasks"].as_array().unwrap().len(), 1);
+    let page: Value = test::call_and_read_body_json(&app, list_page(0)).await;
+    assert_eq!(page["tasks"][0]["name"], "loose end");
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/lists")
+        .insert_header(bearer(&token))
+        .to_request();
+    let lists: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        lists,
+        json!([
+            { "id": 0, "name": "Inbox", "tasks": 1 },
+            { "id": list_id, "name": "Work", "tasks": 1 },
+        ])
+    );
+
+    let delete = |token: &str, cascade: bool| {
+        test::TestRequest::delete()
+            .uri(&format!("/api/v1/lists/{list_id}?cascade={cascade}"))
+            .insert_header(bearer(token))
+            .to_request()
+    };
+    let resp = test::call_service(&app, delete(&other, true)).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = test::call_service(&app, delete(&token, false)).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let resp = test::call_service(&app, delete(&token, true)).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let db = state.read_db();
+    let trashed = db
+        .get_including_deleted(&filed["id"].as_u64().unwrap())
+        .unwrap();
+    assert!(trashed.deleted_at.is_some());
+    assert_eq!(trashed.list_id, 0);
+    assert!(db.lists.is_empty());
+}
+
+// Stands in for a database file that exists but doesn't parse.
+struct CorruptStorage;
+
+#[async_trait]
+impl Storage for CorruptStorage {
+    async fn load(&self) -> io::Result<Option<Database>> {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "expected value"))
+    }
+
+    async fn save(&self, _db: &Database) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[actix_web::test]
+async fn unreadable_storage_only_starts_empty_when_not_strict() {
+    let err = load_database(&CorruptStorage, true).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("TR_STRICT_LOAD"));
+    let db = load_database(&CorruptStorage, false).await.unwrap();
+    assert!(db.tasks.is_empty());
+    // Nothing stored yet is no reason to refuse.
+    assert!(load_database(&MemoryStorage, true).await.is_ok());
+}
+
+// A database with one user and the given tasks, all owned by that user.
+fn stored_database(names: &[&str]) -> Database {
+    let mut db = Database::new();
+    db.insert_user(User {
+        id: 1,
+        username: "ada".to_string(),
+        password_hash: String::new(),
+        role: Role::User,
+        webhook_url: None,
+    });
+    for name in names {
+        let task: Task =
+            serde_json::from_value(json!({ "name": name, "completed": false, "owner_id": 1 }))
+                .unwrap();
+        db.insert_new(task).unwrap();
+    }
+    db
+}
+
+fn task_names(db: &Database) -> Vec<String> {
+    let mut names: Vec<String> = db.tasks.values().map(|task| task.name.clone()).collect();
+    names.sort();
+    names
+}
+
+#[actix_web::test]
+async fn json_storage_replaces_the_file_whole_and_falls_back_to_the_backup() {
+    let dir = env::temp_dir().join(format!("tr-json-storage-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("database.json");
+    let storage = JsonStorage {
+        path: path.clone(),
+        pretty: false,
+    };
+    assert!(storage.load().await.unwrap().is_none());
+    assert_eq!(storage.size().await.unwrap(), None);
+
+    storage.save(&stored_database(&["first"])).await.unwrap();
+    storage
+        .save(&stored_database(&["first", "second"]))
+        .await
+        .unwrap();
+    // The temporary file was renamed over the target, and the previous save kept.
+    let mut files: Vec<String> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["database.json", "database.json.bak"]);
+    let loaded = storage.load().await.unwrap().unwrap();
+    assert_eq!(task_names(&loaded), ["first", "second"]);
+    assert!(loaded.get_user(&1).is_some());
+    assert_eq!(
+        storage.size().await.unwrap(),
+        Some(std::fs::metadata(&path).unwrap().len())
+    );
+
+    std::fs::write(&path, "{ not json").unwrap();
+    let loaded = storage.load().await.unwrap().unwrap();
+    assert_eq!(task_names(&loaded), ["first"]);
+
+    std::fs::write(dir.join("database.json.bak"), "{ not json").unwrap();
+    assert!(storage.load().await.is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[actix_web::test]
+async fn sqlite_storage_round_trips_and_deletes_removed_rows() {
+    let storage = SqliteStorage::connect("sqlite::memory:").await.unwrap();
+    assert!(storage.load().await.unwrap().is_none());
+
+    let mut db = stored_database(&["kept", "removed"]);
+    db.create_list(1, "Errands").unwrap();
+    storage.save(&db).await.unwrap();
+    let loaded = storage.load().await.unwrap().unwrap();
+    assert_eq!(task_names(&loaded), ["kept", "removed"]);
+    assert_eq!(loaded.next_id, db.next_id);
+    assert_eq!(loaded.lists.len(), 1);
+    assert_eq!(loaded.next_list_id, db.next_list_id);
+
+    // Only what changed is written, so rows gone from the database have to be deleted.
+    let removed = db
+        .tasks
+        .values()
+        .find(|task| task.name == "removed")
+        .unwrap()
+        .id;
+    db.tasks.remove(&removed);
+    storage.save(&db).await.unwrap();
+    let loaded = storage.load().await.unwrap().unwrap();
+    assert_eq!(task_names(&loaded), ["kept"]);
+
+    db.remove_user(&1);
+    storage.save(&db).await.unwrap();
+    let loaded = storage.load().await.unwrap().unwrap();
+    assert!(loaded.tasks.is_empty());
+    assert!(loaded.users.is_empty());
+    assert!(loaded.lists.is_empty());
+    assert!(loaded.retired_user_ids.contains(&1));
+}
+
+#[actix_web::test]
+async fn attachments_are_stored_on_disk_and_served_back() {
+    let dir = env::temp_dir().join(format!("tr-attachments-{}", std::process::id()));
+    let state = state_with(Database::new(), |state| {
+        state.attachments_dir = dir.clone();
+        state.max_attachment_bytes = 16;
+    });
+    let token = seed_user(&state, 1, "ada");
+    let other = seed_user(&state, 2, "grace");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "with notes", "completed": false }))
+        .to_request();
+    let task: Value = test::call_and_read_body_json(&app, req).await;
+    let id = task["id"].as_u64().unwrap();
+    let attach = |filename: &str, content: &str| {
+        test::TestRequest::post()
+            .uri(&format!("/api/v1/task/{id}/attachment"))
+            .insert_header(bearer(&token))
+            .set_json(json!({ "filename": filename, "content": content }))
+            .to_request()
+    };
+
+    // "hello world"
+    let resp = test::call_service(&app, attach("notes.txt", "aGVsbG8gd29ybGQ=")).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let attachment: Value = test::read_body_json(resp).await;
+    assert_eq!(attachment["content_type"], "text/plain");
+    assert_eq!(attachment["size"], 11);
+    for (filename, content, status) in [
+        ("run.exe", "aGk=", StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        ("../notes.txt", "aGk=", StatusCode::BAD_REQUEST),
+        (
+            "big.txt",
+            "aGVsbG8gd29ybGQgaGVsbG8gd29ybGQ=",
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ),
+        ("bad.txt", "not base64!", StatusCode::BAD_REQUEST),
+    ] {
+        let resp = test::call_service(&app, attach(filename, content)).await;
+        assert_eq!(resp.status(), status, "{filename}");
+    }
+
+    let download = |token: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/api/v1/task/{id}/attachment/notes.txt"))
+            .insert_header(bearer(token))
+            .to_request()
+    };
+    let resp = test::call_service(&app, download(&token)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/plain"
+    );
+    assert_eq!(
+        resp.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+        "nosniff"
+    );
+    assert_eq!(test::read_body(resp).await, "hello world");
+    let resp = test::call_service(&app, download(&other)).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // A full PUT can't drop or forge the references.
+    let req = test::TestRequest::put()
+        .uri(&format!("/api/v1/task/{id}"))
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "renamed", "completed": false, "version": 2, "attachments": [] }))
+        .to_request();
+    let updated: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(updated["attachments"][0]["name"], "notes.txt");
+
+    state.write_db().tasks.clear();
+    let pruned = prune_attachments(&dir, &HashSet::new()).await.unwrap();
+    assert_eq!(pruned, 1);
+    assert!(!attachment_path(&dir, id, "notes.txt").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[actix_web::test]
+async fn requests_are_counted_and_timed_per_route() {
+    let state = test_state();
+    seed_user(&state, 1, "ada");
+    let token = seed_user(&state, 2, "grace");
+    state.write_db().get_user_mut(&1).unwrap().role = Role::Admin;
+    let admin = state.keyring.issue(1, Role::Admin);
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .wrap_fn(|req, srv| track_requests(req, srv, Duration::MAX))
+            .default_service(web::to(route_not_found))
+            .configure(|cfg| api(cfg, false)),
+    )
+    .await;
+    for id in [1, 2] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/v1/task/{id}"))
+            .insert_header(bearer(&token))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/admin/stats")
+        .insert_header(bearer(&token))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+    let req = test::TestRequest::get()
+        .uri("/api/v1/admin/stats")
+        .insert_header(bearer(&admin))
+        .to_request();
+    let stats: Value = test::call_and_read_body_json(&app, req).await;
+    let routes = stats["routes"].as_array().unwrap();
+    let task = routes
+        .iter()
+        .find(|route| route["route"] == "/api/v1/task/{id}")
+        .unwrap();
+    assert_eq!(task["method"], "GET");
+    assert_eq!(task["count"], 2);
+    let latency = task["latency"].as_array().unwrap();
+    assert_eq!(latency.last().unwrap()["count"], 2);
+    assert!(routes
+        .iter()
+        .any(|route| route["route"] == "/api/v1/admin/stats" && route["count"] == 1));
+}
+
+#[actix_web::test]
+async fn bulk_create_reports_each_item_and_is_multi_status_when_some_fail() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+    let bulk = |body: Value| {
+        test::TestRequest::post()
+            .uri("/api/v1/tasks/bulk")
+            .insert_header(bearer(&token))
+            .set_json(body)
+            .to_request()
+    };
+
+    let resp = test::call_service(
+        &app,
+        bulk(json!([
+            { "name": "fine", "completed": false },
+            { "name": "", "completed": false },
+            { "name": 42 },
+            { "name": "waits", "completed": false, "depends_on": [999] },
+        ])),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(
+        (&body["succeeded"], &body["failed"]),
+        (&json!(1), &json!(3))
+    );
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results[0]["index"], 0);
+    assert!(results[0]["id"].is_u64());
+    assert!(results[0].get("error").is_none());
+    let codes: Vec<_> = results[1..]
+        .iter()
+        .map(|result| (&result["error"]["code"], &result["error"]["status"]))
+        .collect();
+    assert_eq!(
+        codes,
+        [
+            (&json!("invalid_task"), &json!(400)),
+            (&json!("invalid_json"), &json!(400)),
+            (&json!("unknown_dependency"), &json!(400)),
+        ]
+    );
+    assert_eq!(state.read_db().count_for(1), (1, 0));
+
+    let resp =
+        test::call_service(&app, bulk(json!([{ "name": "more", "completed": false }]))).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    for malformed in [json!([]), json!({ "name": "not a list" })] {
+        let resp = test::call_service(&app, bulk(malformed)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+    assert_eq!(state.read_db().count_for(1), (2, 0));
+}
+
+#[actix_web::test]
+async fn import_reports_each_line_like_a_bulk_request() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+    let lines = [
+        json!({ "name": "first", "completed": false }).to_string(),
+        String::new(),
+        "not json".to_string(),
+        json!({ "name": "second", "completed": false }).to_string(),
+    ]
+    .join("\n");
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/tasks/import")
+        .insert_header(bearer(&token))
+        .set_payload(lines)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let body: Value = test::read_body_json(resp).await;
+    let results = body["results"].as_array().unwrap();
+    let indexes: Vec<_> = results.iter().map(|result| &result["index"]).collect();
+    assert_eq!(indexes, [&json!(0), &json!(2), &json!(3)]);
+    assert!(results[0]["id"].is_u64() && results[2]["id"].is_u64());
+    assert_eq!(results[1]["error"]["code"], "invalid_json");
+    assert_eq!(
+        (&body["succeeded"], &body["failed"]),
+        (&json!(2), &json!(1))
+    );
+    assert_eq!(state.read_db().count_for(1), (2, 0));
+}
+
+#[actix_web::test]
+async fn imported_tasks_cannot_depend_on_other_users_tasks() {
+    let state = test_state();
+    let ada = seed_user(&state, 1, "ada");
+    let bob = seed_user(&state, 2, "bob");
+    let app = init_app!(state);
+    let req = test::TestRequest::post()
+        .uri("/api/v1/task")
+        .insert_header(bearer(&bob))
+        .set_json(json!({ "name": "bob's", "completed": false }))
+        .to_request();
+    let theirs: Value = test::call_and_read_body_json(&app, req).await;
+
+    for uri in ["/api/v1/tasks/import", "/api/v1/tasks/import?upsert=true"] {
+        let req = test::TestRequest::post()
+            .uri(uri)
+            .insert_header(bearer(&ada))
+            .set_payload(
+                json!({ "name": "snoop", "completed": false, "depends_on": [theirs["id"]] })
+                    .to_string(),
+            )
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["results"][0]["error"]["code"], "unknown_dependency");
+    }
+    assert_eq!(state.read_db().count_for(1), (0, 0));
+}
+
+#[actix_web::test]
+async fn imported_tasks_cannot_go_into_other_users_lists() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    seed_user(&state, 2, "bob");
+    let theirs = state.write_db().create_list(2, "Work").unwrap();
+    let app = init_app!(state);
+
+    for uri in ["/api/v1/tasks/import", "/api/v1/tasks/import?upsert=true"] {
+        let req = test::TestRequest::post()
+            .uri(uri)
+            .insert_header(bearer(&token))
+            .set_payload(
+                json!({ "name": "misfiled", "completed": false, "list_id": theirs.id }).to_string(),
+            )
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["results"][0]["error"]["code"], "unknown_list");
+    }
+    assert_eq!(state.read_db().count_for(1), (0, 0));
+}
+
+#[actix_web::test]
+async fn a_new_tasks_body_id_does_not_count_as_a_dependency_cycle() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+    let create = |body: Value| {
+        test::TestRequest::post()
+            .uri("/api/v1/task")
+            .insert_header(bearer(&token))
+            .set_json(body)
+            .to_request()
+    };
+    let first: Value = test::call_and_read_body_json(
+        &app,
+        create(json!({ "name": "design", "completed": false })),
+    )
+    .await;
+
+    // A copy of `first` sent back as a new task, depending on the original.
+    let resp = test::call_service(
+        &app,
+        create(json!({ "id": first["id"], "name": "review", "completed": false, "depends_on": [first["id"]] })),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let second: Value = test::read_body_json(resp).await;
+    assert_ne!(second["id"], first["id"]);
+}
+
+#[actix_web::test]
+async fn imports_past_the_size_limit_are_refused() {
+    let state = state_with(Database::new(), |state| state.import_limit_bytes = 64);
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+    let line = json!({ "name": "one of many", "completed": false }).to_string();
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/tasks/import")
+        .insert_header(bearer(&token))
+        .set_payload([line.as_str(); 3].join("\n"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(state.read_db().count_for(1), (0, 0));
+}
+
+#[actix_web::test]
+async fn webhooks_cannot_point_inside_the_deployment() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    for url in [
+        "http://127.0.0.1:8080/hook",
+        "http://localhost/hook",
+        "http://10.0.0.5/hook",
+        "http://169.254.169.254/latest/meta-data",
+        "http://[::1]/hook",
+        "http://[::ffff:192.168.0.1]/hook",
+        "http://0.0.0.0/hook",
+        "http://0.1.2.3/hook",
+        "http://100.64.0.1/hook",
+        "http://224.0.0.1/hook",
+        "http://240.0.0.1/hook",
+        "http://[::10.0.0.1]/hook",
+        "http://[64:ff9b::808:808]/hook",
+        "http://[ff02::1]/hook",
+    ] {
+        let req = test::TestRequest::put()
+            .uri("/api/v1/me/webhook")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "url": url }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{url}");
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"]["code"], "invalid_webhook_url");
+    }
+    assert!(state.read_db().get_user(&1).unwrap().webhook_url.is_none());
+
+    let req = test::TestRequest::put()
+        .uri("/api/v1/me/webhook")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "url": "http://93.184.216.34/hook" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn a_bad_query_string_gets_an_api_error() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks?sort=bogus")
+        .insert_header(bearer(&token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "invalid_query");
+    assert!(body["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("unknown variant"));
+}
+
+#[actix_web::test]
+async fn a_path_id_that_is_not_a_number_is_not_found() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/task/abc")
+        .insert_header(bearer(&token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "not_found");
+}
+
+#[actix_web::test]
+async fn a_poisoned_database_lock_still_serves_requests() {
+    let state = test_state();
+    let token = seed_user(&state, 1, "ada");
+    let poisoner = state.clone();
+    let panicked = std::thread::spawn(move || {
+        let _database = poisoner.write_db();
+        panic!("deliberate, while holding the lock");
+    })
+    .join();
+    assert!(panicked.is_err());
+    assert!(state.db.is_poisoned());
+    let app = init_app!(state);
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/tasks")
+        .insert_header(bearer(&token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn zero_or_malformed_settings_fail_startup() {
+    // A name no other test reads, since tests share the environment.
+    let key = "TR_TEST_NONZERO_SETTING";
+    env::remove_var(key);
+    assert_eq!(env_nonzero(key, 8_usize).unwrap(), 8);
+    env::set_var(key, "12");
+    assert_eq!(env_nonzero(key, 8_usize).unwrap(), 12);
+    for bad in ["0", "eight", "-1"] {
+        env::set_var(key, bad);
+        assert!(env_nonzero(key, 8_usize).is_err(), "{bad}");
+    }
+    env::remove_var(key);
+}
+
+#[actix_web::test]
+async fn registration_uses_the_configured_password_length() {
+    let state = state_with(Database::new(), |state| state.min_password_len = 16);
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/register")
+        .set_json(json!({ "id": 7, "username": "ada", "password": "correct horse" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(resp).await;
+    assert!(body["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("at least 16"));
+}
+
+#[actix_web::test]
+async fn listed_usernames_register_as_admins_and_role_changes_apply_at_once() {
+    let state = state_with(Database::new(), |state| {
+        state.admin_usernames = vec!["Ada".to_string()];
+    });
+    let bob = seed_user(&state, 2, "bob");
+    let app = init_app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/register")
+        .set_json(json!({ "id": 1, "username": "ada", "password": "correct horse" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    assert_eq!(state.read_db().get_user(&1).unwrap().role, Role::Admin);
+    // The role claim is stale; the stored role is what counts.
+    let ada = state.keyring.issue(1, Role::User);
+
+    let list_users = |token: &str| {
+        test::TestRequest::get()
+            .uri("/api/v1/users")
+            .insert_header(bearer(token))
+            .to_request()
+    };
+    let set_role = |role: &str| {
+        test::TestRequest::put()
+            .uri("/api/v1/users/2/role")
+            .insert_header(bearer(&ada))
+            .set_json(json!({ "role": role }))
+            .to_request()
+    };
+    let resp = test::call_service(&app, list_users(&ada)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, list_users(&bob)).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = test::call_service(&app, set_role("admin")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, list_users(&bob)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, set_role("user")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, list_users(&bob)).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn cors_lets_browsers_make_conditional_requests() {
+    let app = test::init_service(
+        App::new()
+            .wrap(build_cors(&["https://app.example".to_string()]))
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let req = test::TestRequest::default()
+        .method(actix_web::http::Method::OPTIONS)
+        .uri("/")
+        .insert_header((header::ORIGIN, "https://app.example"))
+        .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "GET"))
+        .insert_header((
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            "if-none-match, if-match",
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri("/")
+        .insert_header((header::ORIGIN, "https://app.example"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let exposed = resp
+        .headers()
+        .get(header::ACCESS_CONTROL_EXPOSE_HEADERS)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_ascii_lowercase();
+    assert!(exposed.contains("etag") && exposed.contains("last-modified"));
+}
@@ -0,0 +1,224 @@
+use reqwest::{redirect, Client as HttpClient, StatusCode, Url};
+use serde::Serialize;
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use crate::models::Task;
+use crate::notify::NotifyError;
+
+// WEBHOOKS
+// Users can register a URL that is POSTed `{"event": "task.completed", "task": ...}` whenever
+// one of their tasks is completed. Delivery happens in the background and is retried with
+// exponential backoff; a delivery that keeps failing is logged and dropped. The
+// service-wide `WebhookNotifier` delivers the same way.
+//
+// Users' URLs must not reach into the deployment: their host has to resolve to public
+// addresses only, checked when the URL is set and again before every delivery, which then
+// connects to exactly the addresses checked. The operator can exempt hosts by listing them
+// in TR_WEBHOOK_ALLOWED_HOSTS. Redirects are never followed, so a public endpoint can't
+// bounce a delivery inwards. TR_NOTIFY_URL is the operator's own and isn't checked.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_ATTEMPTS: u32 = 4;
+const WEBHOOK_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_WEBHOOK_URL_LEN: usize = 2048;
+
+pub const TASK_COMPLETED: &str = "task.completed";
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    event: &'static str,
+    task: &'a Task,
+}
+
+// Built once at startup and shared, so deliveries reuse pooled connections.
+pub fn http_client() -> reqwest::Result<HttpClient> {
+    HttpClient::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .redirect(redirect::Policy::none())
+        .build()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WebhookUrlError {
+    TooLong,
+    Invalid,
+    Unresolvable,
+    PrivateAddress,
+}
+
+impl fmt::Display for WebhookUrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebhookUrlError::TooLong => write!(
+                f,
+                "Webhook URL must be at most {MAX_WEBHOOK_URL_LEN} characters"
+            ),
+            WebhookUrlError::Invalid => write!(f, "Webhook URL must be an absolute http(s) URL"),
+            WebhookUrlError::Unresolvable => write!(f, "Webhook URL host could not be resolved"),
+            WebhookUrlError::PrivateAddress => {
+                write!(f, "Webhook URL must point at a public address")
+            }
+        }
+    }
+}
+
+pub fn validate_webhook_url(url: &str) -> Result<(), WebhookUrlError> {
+    if url.len() > MAX_WEBHOOK_URL_LEN {
+        return Err(WebhookUrlError::TooLong);
+    }
+    match Url::parse(url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => Ok(()),
+        _ => Err(WebhookUrlError::Invalid),
+    }
+}
+
+// Anything that isn't plainly a public unicast address: "this network" (0/8), loopback,
+// private, link-local, shared (CGNAT), multicast, reserved and broadcast IPv4, and for IPv6
+// the same plus unique-local. IPv6 forms that carry an IPv4 address (mapped, compatible)
+// are judged by it; NAT64 prefixes are refused outright, since the gateway they name can
+// reach whatever IPv4 address they embed.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            a == 0
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_broadcast()
+                || (a == 100 && (64..128).contains(&b))
+                || a >= 240
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            let nat64 = segments[..2] == [0x64, 0xff9b];
+            if let Some(ip) = ip.to_ipv4() {
+                // `::` and `::1` come out as 0.0.0.0 and 0.0.0.1, which are internal too.
+                return is_internal(IpAddr::V4(ip));
+            }
+            nat64
+                || ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+        }
+    }
+}
+
+// Where a delivery may connect: the addresses the host resolved to and passed the check,
+// or None for a host the operator allowed, which is resolved as usual.
+type PinnedAddrs = Option<(String, Vec<SocketAddr>)>;
+
+// Resolves the URL's host and refuses it if any address it resolves to is internal, since
+// the connection may go to any of them. `allowed_hosts` skip the check.
+async fn resolve_webhook_target(
+    url: &str,
+    allowed_hosts: &[String],
+) -> Result<PinnedAddrs, WebhookUrlError> {
+    let url = Url::parse(url).map_err(|_| WebhookUrlError::Invalid)?;
+    let host = url.host_str().ok_or(WebhookUrlError::Invalid)?;
+    if allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        return Ok(None);
+    }
+    let port = url
+        .port_or_known_default()
+        .ok_or(WebhookUrlError::Invalid)?;
+    // IPv6 literals come bracketed, which the resolver doesn't accept.
+    let bare_host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<_> = tokio::net::lookup_host((bare_host, port))
+        .await
+        .map_err(|_| WebhookUrlError::Unresolvable)?
+        .collect();
+    if addrs.is_empty() {
+        return Err(WebhookUrlError::Unresolvable);
+    }
+    if addrs.iter().any(|addr| is_internal(addr.ip())) {
+        return Err(WebhookUrlError::PrivateAddress);
+    }
+    Ok(Some((host.to_string(), addrs)))
+}
+
+pub async fn check_webhook_target(
+    url: &str,
+    allowed_hosts: &[String],
+) -> Result<(), WebhookUrlError> {
+    resolve_webhook_target(url, allowed_hosts).await.map(drop)
+}
+
+// A client that connects only to the checked addresses, so the host can't be made to
+// resolve somewhere else between the check and the connection. Deliveries are rare enough
+// that losing the shared connection pool doesn't matter.
+fn pinned_client(host: &str, addrs: &[SocketAddr]) -> reqwest::Result<HttpClient> {
+    HttpClient::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .redirect(redirect::Policy::none())
+        .resolve_to_addrs(host, addrs)
+        .build()
+}
+
+pub fn spawn_webhook(
+    client: HttpClient,
+    url: String,
+    allowed_hosts: Vec<String>,
+    event: &'static str,
+    task: Task,
+) {
+    tokio::spawn(async move {
+        // The host may resolve elsewhere now than when the URL was set.
+        let client = match resolve_webhook_target(&url, &allowed_hosts).await {
+            Ok(None) => client,
+            Ok(Some((host, addrs))) => match pinned_client(&host, &addrs) {
+                Ok(client) => client,
+                Err(err) => {
+                    tracing::error!(%url, event, task_id = task.id, %err, "failed to build webhook client");
+                    return;
+                }
+            },
+            Err(err) => {
+                tracing::warn!(%url, event, task_id = task.id, %err, "not delivering webhook");
+                return;
+            }
+        };
+        let payload = WebhookPayload { event, task: &task };
+        if let Err(err) = deliver(&client, &url, &payload).await {
+            tracing::error!(%url, event, task_id = task.id, %err, "giving up on webhook delivery");
+        }
+    });
+}
+
+// POSTs `payload` as JSON, retrying network errors, 5xx and 429 with backoff. Other refusals
+// won't change on a retry and fail straight away.
+pub async fn deliver<T>(client: &HttpClient, url: &str, payload: &T) -> Result<(), NotifyError>
+where
+    T: Serialize + ?Sized,
+{
+    let mut backoff = WEBHOOK_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let err = match client.post(url).json(payload).send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => NotifyError::Refused(response.status()),
+            Err(err) => NotifyError::Request(err),
+        };
+        let retryable = match &err {
+            NotifyError::Refused(status) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            NotifyError::Request(_) => true,
+        };
+        if !retryable || attempt == WEBHOOK_ATTEMPTS {
+            return Err(err);
+        }
+        tracing::warn!(%url, attempt, %err, "webhook delivery failed, retrying");
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}